@@ -0,0 +1,199 @@
+use std::fmt;
+
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    options_parser::RunOptions,
+    runner::{Progress, Report, RunError, RunSpec, SessionRun, SourceFile},
+};
+
+/// Somewhere to build and run code
+#[serenity::async_trait]
+pub trait Backend: fmt::Debug + Send + Sync {
+    /// Prepares whatever `spec` runs in. If it's already being built, waits for that build instead
+    async fn build(&self, spec: &RunSpec) -> Result<(), RunError>;
+
+    /// Whether `spec` can be run without building it first
+    async fn image_exists(&self, spec: &RunSpec) -> Result<bool, RunError>;
+
+    /// Gets ready whatever every run needs, so that a misconfiguration shows up at startup instead
+    /// of on the first run. Called once at startup
+    async fn prepare(&self) -> Result<(), RunError> {
+        Ok(())
+    }
+
+    /// Makes sure that every run will get the `Hardening` we were configured with, by checking that
+    /// whatever runs our containers supports it. Called once at startup
+    async fn check_hardening(&self) -> Result<(), RunError>;
+
+    /// Keeps warm containers ready until we shut down. Returns right away for backends that don't
+    /// have any
+    async fn fill_pool(&self) {}
+
+    /// Stops creating containers. Runs that need a new one fail with `RunError::ShuttingDown`
+    fn close(&self);
+
+    /// Force-removes every container we've created, killing whatever's running in them. This
+    /// includes ones left behind by earlier processes that didn't get to clean up. Returns how
+    /// many there were
+    async fn remove_containers(&self) -> Result<usize, RunError>;
+
+    /// Compiles `files` if needed and runs them, sending snapshots of the output to `progress`
+    /// along the way
+    async fn run_code(
+        &self,
+        spec: &RunSpec,
+        files: &[SourceFile<'_>],
+        stdin: Option<&str>,
+        opts: &RunOptions,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Report, RunError>;
+
+    /// Starts `cmd`, a `Language::session_cmd`, in `spec`'s image with the same limits as a run.
+    /// Returns an ID for running snippets in it until it's ended
+    async fn start_session(&self, _spec: &RunSpec, _cmd: &[String]) -> Result<String, RunError> {
+        Err(RunError::SessionsUnsupported)
+    }
+
+    /// Runs `code` in the session, where everything earlier snippets defined is still around.
+    /// Each snippet gets a run's time and output limits. Going over them ends the session
+    async fn run_in_session(
+        &self,
+        _id: &str,
+        _code: &str,
+        _stdin: Option<&str>,
+        _progress: Option<&Sender<Progress>>,
+    ) -> Result<SessionRun, RunError> {
+        Err(RunError::SessionEnded)
+    }
+
+    /// Kills the session. Does nothing if it's already gone
+    async fn end_session(&self, _id: &str) -> Result<(), RunError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub use fake::FakeBackend;
+
+#[cfg(test)]
+mod fake {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::runner::{Chunk, Output, Termination};
+
+    /// Pretends to run code. Every program prints the files it was given followed by its stdin.
+    /// Snippets in a session print every snippet the session has run
+    #[derive(Debug, Default)]
+    pub struct FakeBackend {
+        /// Everything that's been built
+        pub images: Mutex<HashSet<String>>,
+        /// Makes every build fail with this error
+        pub build_error: Option<&'static str>,
+        /// Makes every run fail as if the daemon gave this error
+        pub run_error: Option<&'static str>,
+        /// The code each session has run, by ID
+        pub sessions: Mutex<HashMap<String, String>>,
+    }
+
+    #[serenity::async_trait]
+    impl Backend for FakeBackend {
+        async fn build(&self, spec: &RunSpec) -> Result<(), RunError> {
+            if let Some(err) = self.build_error {
+                return Err(RunError::build(err));
+            }
+            self.images.lock().unwrap().insert(spec.image());
+            Ok(())
+        }
+
+        async fn image_exists(&self, spec: &RunSpec) -> Result<bool, RunError> {
+            Ok(self.images.lock().unwrap().contains(&spec.image()))
+        }
+
+        async fn check_hardening(&self) -> Result<(), RunError> {
+            Ok(())
+        }
+
+        fn close(&self) {}
+
+        async fn remove_containers(&self) -> Result<usize, RunError> {
+            Ok(0)
+        }
+
+        async fn run_code(
+            &self,
+            spec: &RunSpec,
+            files: &[SourceFile<'_>],
+            stdin: Option<&str>,
+            opts: &RunOptions,
+            _progress: Option<&Sender<Progress>>,
+        ) -> Result<Report, RunError> {
+            if !self.image_exists(spec).await? {
+                return Err(RunError::ImageMissing);
+            }
+            if let Some(err) = self.run_error {
+                return Err(RunError::daemon(err));
+            }
+            let mut stdout: String = files.iter().map(|f| format!("{}\n", f.path)).collect();
+            stdout.push_str(stdin.unwrap_or_default());
+            let mut tty = vec![Chunk::Stdout(stdout.into())];
+            if !opts.args.is_empty() {
+                tty.push(Chunk::Stderr(format!("{:?}\n", opts.args).into()));
+            }
+            Ok(Report {
+                compile: None,
+                run: Some(Output {
+                    status: 0,
+                    termination: Termination::Exited(0),
+                    tty,
+                    usage: None,
+                    files: Default::default(),
+                }),
+            })
+        }
+
+        async fn start_session(&self, spec: &RunSpec, _cmd: &[String]) -> Result<String, RunError> {
+            if !self.image_exists(spec).await? {
+                return Err(RunError::ImageMissing);
+            }
+            let mut sessions = self.sessions.lock().unwrap();
+            let id = (0..)
+                .map(|n| format!("session-{}", n))
+                .find(|id| !sessions.contains_key(id))
+                .unwrap();
+            sessions.insert(id.clone(), String::new());
+            Ok(id)
+        }
+
+        async fn run_in_session(
+            &self,
+            id: &str,
+            code: &str,
+            _stdin: Option<&str>,
+            _progress: Option<&Sender<Progress>>,
+        ) -> Result<SessionRun, RunError> {
+            let mut sessions = self.sessions.lock().unwrap();
+            let history = sessions.get_mut(id).ok_or(RunError::SessionEnded)?;
+            history.push_str(code);
+            Ok(SessionRun {
+                output: Output {
+                    status: 0,
+                    termination: Termination::Exited(0),
+                    tty: vec![Chunk::Stdout(history.as_str().into())],
+                    usage: None,
+                    files: Default::default(),
+                },
+                ended: false,
+            })
+        }
+
+        async fn end_session(&self, id: &str) -> Result<(), RunError> {
+            self.sessions.lock().unwrap().remove(id);
+            Ok(())
+        }
+    }
+}
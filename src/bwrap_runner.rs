@@ -0,0 +1,566 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Write as _},
+    mem::ManuallyDrop,
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::ExitStatusExt,
+    },
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use futures::stream;
+use shiplift::tty::TtyChunk;
+use tempfile::TempDir;
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+    sync::{mpsc::Sender, Mutex},
+};
+
+use crate::{
+    backend::Backend,
+    options_parser::RunOptions,
+    runner::{
+        read_chunks, supervise, Exit, Hardening, Output, Phase, ProcessLimits, Progress, Report,
+        RunError, RunIds, RunSpec, SourceFile, Termination,
+    },
+};
+
+/// The `PATH` programs get unless the rootfs's environment file says otherwise
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Runs code with bubblewrap instead of Docker. Every language needs a root filesystem at
+/// `{rootfs_dir}/{image_name}`, like one made with `docker export`. Since that loses the image's
+/// `ENV`, variables can be given one per line in `{rootfs_dir}/{image_name}.env`
+#[derive(Debug)]
+pub struct BwrapRunner {
+    pub rootfs_dir: PathBuf,
+    /// A cgroup v2 directory we're allowed to manage. Each run gets its own cgroup in here with
+    /// our CPU, memory and process count limits. Without one, there are no such limits
+    pub cgroup_root: Option<PathBuf>,
+    pub timeout: Duration,
+    pub compile_timeout: Duration,
+    pub cpus: f64,
+    pub memory_bytes: u64,
+    pub max_output_bytes: usize,
+    /// The process count limit needs a cgroup, but the rest are always applied
+    pub limits: ProcessLimits,
+    /// bwrap always sets no_new_privs, but the rest of it can't be applied
+    pub hardening: Hardening,
+    pub run_ids: RunIds,
+}
+
+#[serenity::async_trait]
+impl Backend for BwrapRunner {
+    async fn build(&self, spec: &RunSpec) -> Result<(), RunError> {
+        Err(RunError::build(format!(
+            "there is no root filesystem for `{}` at `{}`, and I can't build one without Docker",
+            spec.image_name,
+            self.rootfs(spec).display(),
+        )))
+    }
+
+    async fn image_exists(&self, spec: &RunSpec) -> Result<bool, RunError> {
+        Ok(self.rootfs(spec).is_dir())
+    }
+
+    async fn check_hardening(&self) -> Result<(), RunError> {
+        let Hardening {
+            seccomp,
+            apparmor,
+            selinux_label,
+            ..
+        } = &self.hardening;
+        if *seccomp || apparmor.is_some() || selinux_label.is_some() {
+            return Err(RunError::Unhardened(
+                "the bubblewrap backend only supports no_new_privileges".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.run_ids.close();
+    }
+
+    /// There are no containers, but each run's cgroup is the closest thing. They're named after
+    /// run IDs, which start with our instance name. Without cgroups, sandboxes die along with us
+    /// thanks to `--die-with-parent`
+    async fn remove_containers(&self) -> Result<usize, RunError> {
+        let root = match &self.cgroup_root {
+            Some(root) => root,
+            None => return Ok(0),
+        };
+        let prefix = format!("run-{}-", self.run_ids.instance());
+        let mut removed = 0;
+        for entry in fs::read_dir(root).map_err(RunError::cleanup)? {
+            let entry = entry.map_err(RunError::cleanup)?;
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                Cgroup { path: entry.path() }
+                    .remove()
+                    .await
+                    .map_err(RunError::cleanup)?;
+                tracing::info!("Removed cgroup {}", entry.path().display());
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn run_code(
+        &self,
+        spec: &RunSpec,
+        files: &[SourceFile<'_>],
+        stdin: Option<&str>,
+        opts: &RunOptions,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Report, RunError> {
+        if !self.image_exists(spec).await? {
+            return Err(RunError::ImageMissing);
+        }
+        let mut env = vec![format!("PATH={}", DEFAULT_PATH), "HOME=/tmp".to_owned()];
+        match fs::read_to_string(self.rootfs_dir.join(format!("{}.env", spec.image_name))) {
+            Ok(text) => env.extend(
+                text.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(str::to_owned),
+            ),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(RunError::daemon(err)),
+        }
+        env.extend(
+            opts.env
+                .iter()
+                .map(|(name, val)| format!("{}={}", name, val)),
+        );
+
+        // Bind mounted as /tmp, so it's shared between phases
+        let workdir = write_workdir(files).map_err(RunError::copy)?;
+
+        let compile = match &spec.compile {
+            Some(cmd) => {
+                let phase = Phase {
+                    cmd: cmd.iter().map(String::as_str).collect(),
+                    env: &env,
+                    stdin: None,
+                    timeout: self.compile_timeout,
+                    compiling: true,
+                };
+                let output = self
+                    .run_sandbox(spec, &phase, workdir.path(), progress)
+                    .await?;
+                if !output.success() {
+                    return Ok(Report {
+                        compile: Some(output),
+                        run: None,
+                    });
+                }
+                Some(output)
+            }
+            None => None,
+        };
+
+        let phase = Phase {
+            cmd: spec
+                .run
+                .iter()
+                .chain(&opts.args)
+                .map(String::as_str)
+                .collect(),
+            env: &env,
+            stdin,
+            timeout: self.timeout,
+            compiling: false,
+        };
+        let run = self
+            .run_sandbox(spec, &phase, workdir.path(), progress)
+            .await?;
+        Ok(Report {
+            compile,
+            run: Some(run),
+        })
+    }
+}
+
+impl BwrapRunner {
+    fn rootfs(&self, spec: &RunSpec) -> PathBuf {
+        self.rootfs_dir.join(&spec.image_name)
+    }
+
+    /// The arguments to `bwrap` that run `phase` in `rootfs` with `workdir` as its /tmp
+    fn bwrap_args(rootfs: &Path, workdir: &Path, phase: &Phase<'_>) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            // New user, PID, IPC, UTS, cgroup and network namespaces, so no internet access
+            "--unshare-all".into(),
+            "--die-with-parent".into(),
+            // Keeps the program from pushing input into our terminal
+            "--new-session".into(),
+            // Run as user "nobody"
+            "--uid".into(),
+            "65534".into(),
+            "--gid".into(),
+            "65534".into(),
+            // Ensure that we are unprivileged
+            "--cap-drop".into(),
+            "ALL".into(),
+            // The toolchain can't be modified
+            "--ro-bind".into(),
+            rootfs.into(),
+            "/".into(),
+            "--proc".into(),
+            "/proc".into(),
+            "--dev".into(),
+            "/dev".into(),
+            // Be in a safe directory
+            "--bind".into(),
+            workdir.into(),
+            "/tmp".into(),
+            "--chdir".into(),
+            "/tmp".into(),
+            // Don't leak our environment, like the Discord token
+            "--clearenv".into(),
+        ];
+        for var in phase.env {
+            let (name, val) = var.split_once('=').unwrap_or((var, ""));
+            args.extend(["--setenv".into(), name.into(), val.into()]);
+        }
+        args.push("--".into());
+        args.extend(phase.cmd.iter().map(OsString::from));
+        args
+    }
+
+    async fn run_sandbox(
+        &self,
+        spec: &RunSpec,
+        phase: &Phase<'_>,
+        workdir: &Path,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Output, RunError> {
+        let cgroup = match &self.cgroup_root {
+            Some(root) => Some(
+                Cgroup::create(
+                    root,
+                    &self.run_ids.next()?,
+                    self.cpus,
+                    self.memory_bytes,
+                    self.limits.pids,
+                )
+                .map_err(RunError::daemon)?,
+            ),
+            None => None,
+        };
+
+        let mut cmd = Command::new("bwrap");
+        cmd.args(Self::bwrap_args(&self.rootfs(spec), workdir, phase))
+            .stdin(if phase.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        // Joining the cgroup before exec means everything bwrap starts is limited from the
+        // beginning. Writing 0 to `cgroup.procs` moves the writer
+        let procs = match &cgroup {
+            Some(cgroup) => Some(
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(cgroup.path.join("cgroup.procs"))
+                    .map_err(RunError::daemon)?,
+            ),
+            None => None,
+        };
+        let procs_fd = procs.as_ref().map(|procs| procs.as_raw_fd());
+        // bwrap and everything it starts inherit these
+        let rlimits: Vec<_> = [
+            (libc::RLIMIT_NOFILE, self.limits.nofile),
+            (libc::RLIMIT_NPROC, self.limits.nproc),
+            (libc::RLIMIT_FSIZE, self.limits.fsize),
+        ]
+        .into_iter()
+        .filter(|&(_, limit)| limit > 0)
+        .collect();
+        // SAFETY: this only makes setrlimit and write syscalls, which are fine between fork and
+        // exec. `procs` outlives `spawn`, and `ManuallyDrop` keeps the child from closing it
+        unsafe {
+            cmd.pre_exec(move || {
+                for &(resource, limit) in &rlimits {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit,
+                        rlim_max: limit,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(fd) = procs_fd {
+                    ManuallyDrop::new(fs::File::from_raw_fd(fd)).write_all(b"0")?;
+                }
+                Ok(())
+            });
+        }
+        let child = cmd.spawn().map_err(RunError::daemon)?;
+        drop(procs);
+        self.supervise_child(child, phase, progress, cgroup.as_ref())
+            .await
+    }
+
+    async fn supervise_child(
+        &self,
+        mut child: Child,
+        phase: &Phase<'_>,
+        progress: Option<&Sender<Progress>>,
+        cgroup: Option<&Cgroup>,
+    ) -> Result<Output, RunError> {
+        let name = format!("sandbox {}", child.id().unwrap_or_default());
+        tracing::info!("{} started", name);
+        if let Some(stdin) = phase.stdin {
+            // Dropping our end closes the program's stdin
+            let mut writer = child.stdin.take().unwrap();
+            writer
+                .write_all(stdin.as_bytes())
+                .await
+                .map_err(RunError::copy)?;
+        }
+        let logs = stream::select(
+            read_chunks(child.stdout.take().unwrap(), TtyChunk::StdOut),
+            read_chunks(child.stderr.take().unwrap(), TtyChunk::StdErr),
+        );
+
+        let child = Mutex::new(child);
+        let output = supervise(
+            &name,
+            Box::pin(logs),
+            phase,
+            self.max_output_bytes,
+            progress,
+            || async {
+                let status = child.lock().await.wait().await.map_err(RunError::daemon)?;
+                let signal = status.signal();
+                Ok(Exit {
+                    // Like Docker, report death by signal N as 128 + N
+                    status: status
+                        .code()
+                        .or_else(|| signal.map(|sig| 128 + sig))
+                        .unwrap_or_default() as u64,
+                    signal: signal.map(|sig| sig as u8),
+                })
+            },
+            || async {
+                match cgroup {
+                    // Gets anything that escaped bwrap's PID namespace too
+                    Some(cgroup) => cgroup.kill(),
+                    None => child.lock().await.start_kill(),
+                }
+                .map_err(RunError::daemon)
+            },
+        )
+        .await;
+        tracing::info!("{} finished", name);
+        let mut output = output?;
+        // The cgroup's accounting covers everything the program started
+        if let (Some(cgroup), Some(usage)) = (cgroup, &mut output.usage) {
+            usage.cpu = cgroup.cpu_time().map_err(RunError::daemon)?;
+            usage.peak_memory_bytes = cgroup.peak_memory_bytes().map_err(RunError::daemon)?;
+        }
+        if let Some(cgroup) = cgroup {
+            let oom_killed = cgroup.oom_killed().map_err(RunError::daemon)?;
+            if output.termination.maybe_out_of_memory() && oom_killed {
+                output.termination = Termination::OutOfMemory;
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// A temporary directory holding `files`
+fn write_workdir(files: &[SourceFile<'_>]) -> io::Result<TempDir> {
+    let workdir = tempfile::tempdir()?;
+    for file in files {
+        let path = workdir.path().join(file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, file.contents)?;
+    }
+    Ok(workdir)
+}
+
+/// A cgroup for a single run, removed when dropped
+#[derive(Debug)]
+struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    fn create(
+        root: &Path,
+        run_id: &str,
+        cpus: f64,
+        memory_bytes: u64,
+        pids: u64,
+    ) -> io::Result<Self> {
+        // Children only get the controllers their parent hands down
+        fs::write(root.join("cgroup.subtree_control"), "+cpu +memory +pids")?;
+        let path = root.join(format!("run-{}", run_id));
+        fs::create_dir(&path)?;
+        let cgroup = Self { path };
+        for (file, value) in Self::limits(cpus, memory_bytes, pids) {
+            fs::write(cgroup.path.join(file), value)?;
+        }
+        Ok(cgroup)
+    }
+
+    /// The files to write to apply our limits. Zero means no limit, like it does for Docker
+    fn limits(cpus: f64, memory_bytes: u64, pids: u64) -> Vec<(&'static str, String)> {
+        const PERIOD: u64 = 100_000;
+        let mut limits = Vec::new();
+        if cpus > 0.0 {
+            let quota = (cpus * PERIOD as f64) as u64;
+            limits.push(("cpu.max", format!("{} {}", quota, PERIOD)));
+        }
+        if memory_bytes > 0 {
+            limits.push(("memory.max", memory_bytes.to_string()));
+        }
+        if pids > 0 {
+            limits.push(("pids.max", pids.to_string()));
+        }
+        limits
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        fs::write(self.path.join("cgroup.kill"), "1")
+    }
+
+    /// Kills everything in the cgroup and removes it, waiting for its processes to die first
+    async fn remove(self) -> io::Result<()> {
+        self.kill()?;
+        let mut tries = 0;
+        loop {
+            match fs::remove_dir(&self.path) {
+                Ok(()) => break,
+                // Still has processes in it
+                Err(err) if err.kind() == io::ErrorKind::ResourceBusy && tries < 20 => {
+                    tries += 1;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        // Nothing left for `drop` to do
+        std::mem::forget(self);
+        Ok(())
+    }
+
+    fn cpu_time(&self) -> io::Result<Option<Duration>> {
+        let stat = fs::read_to_string(self.path.join("cpu.stat"))?;
+        Ok(parse_cpu_stat(&stat))
+    }
+
+    /// Whether the kernel killed anything in here for using too much memory
+    fn oom_killed(&self) -> io::Result<bool> {
+        let events = fs::read_to_string(self.path.join("memory.events"))?;
+        Ok(parse_stat(&events, "oom_kill").unwrap_or_default() > 0)
+    }
+
+    /// Only newer kernels (5.19+) keep track of this
+    fn peak_memory_bytes(&self) -> io::Result<Option<u64>> {
+        match fs::read_to_string(self.path.join("memory.peak")) {
+            Ok(peak) => Ok(peak.trim().parse().ok()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Gets a counter out of a flat keyed file like `cpu.stat` or `memory.events`
+fn parse_stat(stat: &str, key: &str) -> Option<u64> {
+    stat.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Gets the total CPU time out of a `cpu.stat` file
+fn parse_cpu_stat(stat: &str) -> Option<Duration> {
+    parse_stat(stat, "usage_usec").map(Duration::from_micros)
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir(&self.path) {
+            tracing::warn!("failed to remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bwrap_args() {
+        let env = ["PATH=/bin".to_owned(), "A=1=2".to_owned()];
+        let phase = Phase {
+            cmd: vec!["python", "run.py", "two words"],
+            env: &env,
+            stdin: None,
+            timeout: Duration::from_secs(10),
+            compiling: false,
+        };
+        let args = BwrapRunner::bwrap_args(
+            Path::new("/srv/rootfs/python"),
+            Path::new("/tmp/work"),
+            &phase,
+        );
+        let args: Vec<&str> = args.iter().map(|a| a.to_str().unwrap()).collect();
+        for window in [
+            &["--unshare-all"][..],
+            &["--uid", "65534"],
+            &["--cap-drop", "ALL"],
+            &["--ro-bind", "/srv/rootfs/python", "/"],
+            &["--bind", "/tmp/work", "/tmp"],
+            &["--setenv", "A", "1=2"],
+        ] {
+            assert!(
+                args.windows(window.len()).any(|w| w == window),
+                "{:?} missing from {:?}",
+                window,
+                args
+            );
+        }
+        // The command comes last, untouched
+        assert_eq!(
+            args[args.len() - 4..],
+            ["--", "python", "run.py", "two words"]
+        );
+    }
+
+    #[test]
+    fn test_cgroup_limits() {
+        assert_eq!(
+            Cgroup::limits(1.5, 1 << 20, 64),
+            [
+                ("cpu.max", "150000 100000".to_owned()),
+                ("memory.max", "1048576".to_owned()),
+                ("pids.max", "64".to_owned())
+            ]
+        );
+        assert!(Cgroup::limits(0.0, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "usage_usec 1500000\nuser_usec 1000000\nsystem_usec 500000\n";
+        assert_eq!(parse_cpu_stat(stat), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_cpu_stat(""), None);
+
+        let events = "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(parse_stat(events, "oom_kill"), Some(1));
+        assert_eq!(parse_stat(events, "oom"), Some(1));
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    fmt, fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use futures::stream;
+use shiplift::tty::TtyChunk;
+use tempfile::TempDir;
+use tokio::{io::AsyncWriteExt, process::Command, sync::mpsc::Sender};
+
+use crate::{
+    backend::Backend,
+    options_parser::RunOptions,
+    runner::{
+        read_chunks, supervise, ErrorSource, Exit, Hardening, InFlightBuilds, Output, Phase,
+        ProcessLimits, Progress, Report, RunError, RunIds, RunSpec, SourceFile, Termination,
+        INSTANCE_LABEL, RUN_ID_LABEL,
+    },
+};
+
+/// Runs code by driving the `podman` or `docker` command line, for hosts without a Docker socket
+/// (e.g. rootless Podman). Containers get the same isolation as `DockerRunner`'s, but only their
+/// wall time is measured
+pub struct CliRunner {
+    /// The CLI to use, like `podman` or `docker`
+    pub program: String,
+    pub timeout: Duration,
+    pub compile_timeout: Duration,
+    pub cpus: f64,
+    pub memory_bytes: u64,
+    pub max_output_bytes: usize,
+    pub limits: ProcessLimits,
+    /// None of it can be applied yet, so asking for any makes `check_hardening` fail
+    pub hardening: Hardening,
+    pub builds: InFlightBuilds,
+    pub run_ids: RunIds,
+}
+
+impl fmt::Debug for CliRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CliRunner")
+            .field("program", &self.program)
+            .field("timeout", &self.timeout)
+            .field("compile_timeout", &self.compile_timeout)
+            .field("cpus", &self.cpus)
+            .field("memory_bytes", &self.memory_bytes)
+            .field("max_output_bytes", &self.max_output_bytes)
+            .field("limits", &self.limits)
+            .field("hardening", &self.hardening)
+            .finish_non_exhaustive()
+    }
+}
+
+#[serenity::async_trait]
+impl Backend for CliRunner {
+    async fn build(&self, spec: &RunSpec) -> Result<(), RunError> {
+        self.builds
+            .coalesce(&spec.image(), self.build_image(spec))
+            .await
+    }
+
+    async fn image_exists(&self, spec: &RunSpec) -> Result<bool, RunError> {
+        let status = Command::new(&self.program)
+            .args(["image", "inspect", &spec.image()])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await
+            .map_err(RunError::daemon)?;
+        Ok(status.success())
+    }
+
+    async fn check_hardening(&self) -> Result<(), RunError> {
+        if self.hardening.security_opts().is_empty() {
+            Ok(())
+        } else {
+            Err(RunError::Unhardened(format!(
+                "the {} backend doesn't support any",
+                self.program
+            )))
+        }
+    }
+
+    fn close(&self) {
+        self.run_ids.close();
+    }
+
+    async fn remove_containers(&self) -> Result<usize, RunError> {
+        let ids = self
+            .cli(&[
+                "ps",
+                "--all",
+                "--quiet",
+                &format!(
+                    "--filter=label={}={}",
+                    INSTANCE_LABEL,
+                    self.run_ids.instance()
+                ),
+            ])
+            .await
+            .map_err(RunError::Daemon)?;
+        let ids: Vec<&str> = ids.split_whitespace().collect();
+        if !ids.is_empty() {
+            let mut args = vec!["rm", "--force"];
+            args.extend(&ids);
+            self.cli(&args).await.map_err(RunError::Cleanup)?;
+            tracing::info!("Removed containers {:?}", ids);
+        }
+        Ok(ids.len())
+    }
+
+    async fn run_code(
+        &self,
+        spec: &RunSpec,
+        files: &[SourceFile<'_>],
+        stdin: Option<&str>,
+        opts: &RunOptions,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Report, RunError> {
+        if !self.image_exists(spec).await? {
+            return Err(RunError::ImageMissing);
+        }
+        let env: Vec<String> = opts
+            .env
+            .iter()
+            .map(|(name, val)| format!("{}={}", name, val))
+            .collect();
+
+        // The working directory lives on the host between containers
+        let workdir = write_workdir(files).map_err(RunError::copy)?;
+
+        let compile = match &spec.compile {
+            Some(cmd) => {
+                let phase = Phase {
+                    cmd: cmd.iter().map(String::as_str).collect(),
+                    env: &env,
+                    stdin: None,
+                    timeout: self.compile_timeout,
+                    compiling: true,
+                };
+                let output = self
+                    .run_container(spec, &phase, workdir.path(), true, progress)
+                    .await?;
+                if !output.success() {
+                    return Ok(Report {
+                        compile: Some(output),
+                        run: None,
+                    });
+                }
+                Some(output)
+            }
+            None => None,
+        };
+
+        let phase = Phase {
+            cmd: spec
+                .run
+                .iter()
+                .chain(&opts.args)
+                .map(String::as_str)
+                .collect(),
+            env: &env,
+            stdin,
+            timeout: self.timeout,
+            compiling: false,
+        };
+        let run = self
+            .run_container(spec, &phase, workdir.path(), false, progress)
+            .await?;
+        Ok(Report {
+            compile,
+            run: Some(run),
+        })
+    }
+}
+
+impl CliRunner {
+    async fn build_image(&self, spec: &RunSpec) -> Result<(), RunError> {
+        let dir = tempfile::tempdir().map_err(RunError::build)?;
+        fs::write(dir.path().join("Dockerfile"), &spec.dockerfile).map_err(RunError::build)?;
+        let image = spec.image();
+        tracing::info!("Building {}", image);
+        self.cli(&["build", "--tag", &image, path_str(dir.path())])
+            .await
+            .map_err(RunError::Build)?;
+        Ok(())
+    }
+
+    /// Runs the CLI to completion, returning its trimmed stdout
+    async fn cli(&self, args: &[&str]) -> Result<String, ErrorSource> {
+        let output = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(format!(
+                "`{} {}` failed: {}",
+                self.program,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim(),
+            )
+            .into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// The arguments that create a container for `phase` with all of our isolation settings
+    fn create_args(&self, image: &str, run_id: &str, phase: &Phase<'_>) -> Vec<String> {
+        let mut args: Vec<String> = vec![
+            "create".into(),
+            format!("--label={}={}", RUN_ID_LABEL, run_id),
+            format!("--label={}={}", INSTANCE_LABEL, self.run_ids.instance()),
+            // Only ever use images we built ourselves
+            "--pull=never".into(),
+            // Run as user "nobody"
+            "--user=65534:65534".into(),
+            // Ensure that we are unprivileged
+            "--cap-drop=ALL".into(),
+            // No internet access
+            "--network=none".into(),
+            // Be in a safe directory
+            "--workdir=/tmp".into(),
+            // Stop immediately
+            "--stop-signal=SIGKILL".into(),
+            "--stop-timeout=0".into(),
+        ];
+        // Don't take too many resources. Zero means no limit, like it does for the Docker API
+        if self.cpus > 0.0 {
+            args.push(format!("--cpus={}", self.cpus));
+        }
+        if self.memory_bytes > 0 {
+            args.push(format!("--memory={}b", self.memory_bytes));
+        }
+        if self.limits.pids > 0 {
+            args.push(format!("--pids-limit={}", self.limits.pids));
+        }
+        for (name, limit) in self.limits.rlimits() {
+            args.push(format!("--ulimit={}={}:{}", name, limit, limit));
+        }
+        // Only give the program a stdin if we have something to feed it
+        if phase.stdin.is_some() {
+            args.push("--interactive".into());
+        }
+        for var in phase.env {
+            args.push(format!("--env={}", var));
+        }
+        args.push(image.into());
+        args.extend(phase.cmd.iter().map(|&arg| arg.into()));
+        args
+    }
+
+    /// Runs a single command in a fresh container with `workdir` as its working directory. If
+    /// `save_workdir`, the working directory is copied back into `workdir` afterwards
+    async fn run_container(
+        &self,
+        spec: &RunSpec,
+        phase: &Phase<'_>,
+        workdir: &Path,
+        save_workdir: bool,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Output, RunError> {
+        let args = self.create_args(&spec.image(), &self.run_ids.next()?, phase);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let id = self.cli(&args).await.map_err(RunError::Daemon)?;
+        let result = self
+            .start_container(&id, phase, workdir, save_workdir, progress)
+            .await;
+        match self.cli(&["rm", "--force", &id]).await {
+            Ok(_) => tracing::info!("{} removed", id),
+            // Reporting the run's own error is more useful
+            Err(err) if result.is_err() => tracing::warn!("Failed to remove {}: {}", id, err),
+            Err(err) => return Err(RunError::Cleanup(err)),
+        }
+        result
+    }
+
+    async fn start_container(
+        &self,
+        id: &str,
+        phase: &Phase<'_>,
+        workdir: &Path,
+        save_workdir: bool,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Output, RunError> {
+        let mut src = PathBuf::from(workdir);
+        src.push(".");
+        self.cli(&["cp", path_str(&src), &format!("{}:/tmp", id)])
+            .await
+            .map_err(RunError::Copy)?;
+
+        tracing::info!("{} starting", id);
+        let mut start = Command::new(&self.program);
+        start.args(["start", "--attach"]);
+        if phase.stdin.is_some() {
+            start.arg("--interactive");
+        }
+        let mut child = start
+            .arg(id)
+            .stdin(if phase.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(RunError::daemon)?;
+        if let Some(stdin) = phase.stdin {
+            // Dropping our end closes the program's stdin
+            let mut writer = child.stdin.take().unwrap();
+            writer
+                .write_all(stdin.as_bytes())
+                .await
+                .map_err(RunError::copy)?;
+        }
+        let logs = stream::select(
+            read_chunks(child.stdout.take().unwrap(), TtyChunk::StdOut),
+            read_chunks(child.stderr.take().unwrap(), TtyChunk::StdErr),
+        );
+
+        let mut output = supervise(
+            &id,
+            Box::pin(logs),
+            phase,
+            self.max_output_bytes,
+            progress,
+            || async {
+                // Like Docker's API, this can't tell death by signal N from exiting with 128 + N
+                let status = self.cli(&["wait", id]).await.map_err(RunError::Daemon)?;
+                status.parse().map(Exit::status).map_err(RunError::daemon)
+            },
+            || async {
+                // The program may have exited on its own in the meantime
+                if let Err(err) = self.cli(&["kill", id]).await {
+                    tracing::warn!("{}", err);
+                }
+                Ok(())
+            },
+        )
+        .await?;
+        child.wait().await.map_err(RunError::daemon)?;
+        if output.termination.maybe_out_of_memory() {
+            let oom_killed = self
+                .cli(&["inspect", "--format={{.State.OOMKilled}}", id])
+                .await
+                .map_err(RunError::Daemon)?;
+            if oom_killed == "true" {
+                output.termination = Termination::OutOfMemory;
+            }
+        }
+
+        if save_workdir {
+            self.cli(&["cp", &format!("{}:/tmp/.", id), path_str(workdir)])
+                .await
+                .map_err(RunError::Copy)?;
+        }
+        Ok(output)
+    }
+}
+
+/// A temporary directory holding `files`
+fn write_workdir(files: &[SourceFile<'_>]) -> io::Result<TempDir> {
+    let workdir = tempfile::tempdir()?;
+    // Copying a directory into a container copies its permissions too, and the program needs to
+    // be able to write to its working directory
+    fs::set_permissions(workdir.path(), fs::Permissions::from_mode(0o1777))?;
+    for file in files {
+        let path = workdir.path().join(file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, file.contents)?;
+    }
+    Ok(workdir)
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("temporary paths are UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runner() -> CliRunner {
+        CliRunner {
+            program: "podman".into(),
+            timeout: Duration::from_secs(10),
+            compile_timeout: Duration::from_secs(30),
+            cpus: 1.5,
+            memory_bytes: 1 << 20,
+            max_output_bytes: 1024,
+            limits: ProcessLimits::default(),
+            hardening: Default::default(),
+            builds: Default::default(),
+            run_ids: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_create_args() {
+        let env = ["A=1".to_owned()];
+        let phase = Phase {
+            cmd: vec!["python", "run.py", "two words"],
+            env: &env,
+            stdin: Some("hi"),
+            timeout: Duration::from_secs(10),
+            compiling: false,
+        };
+        let args = runner().create_args("codie/python:abc", "1-0", &phase);
+        for arg in [
+            "--label=codie.run_id=1-0",
+            "--label=codie.instance=codie",
+            "--user=65534:65534",
+            "--cap-drop=ALL",
+            "--network=none",
+            "--stop-signal=SIGKILL",
+            "--cpus=1.5",
+            "--memory=1048576b",
+            "--pids-limit=256",
+            "--ulimit=nofile=4096:4096",
+            "--ulimit=nproc=2048:2048",
+            "--ulimit=fsize=67108864:67108864",
+            "--interactive",
+            "--env=A=1",
+        ] {
+            assert!(
+                args.iter().any(|a| a == arg),
+                "{} missing from {:?}",
+                arg,
+                args
+            );
+        }
+        // The command comes last, untouched
+        assert_eq!(
+            args[args.len() - 4..],
+            ["codie/python:abc", "python", "run.py", "two words"]
+        );
+    }
+
+    #[test]
+    fn test_create_args_unlimited() {
+        let phase = Phase {
+            cmd: vec!["./main"],
+            env: &[],
+            stdin: None,
+            timeout: Duration::from_secs(10),
+            compiling: false,
+        };
+        let args = CliRunner {
+            cpus: 0.0,
+            memory_bytes: 0,
+            limits: ProcessLimits {
+                pids: 0,
+                nofile: 0,
+                nproc: 0,
+                fsize: 0,
+            },
+            ..runner()
+        }
+        .create_args("codie/c-gcc:abc", "1-0", &phase);
+        assert!(
+            !args.iter().any(|a| a.starts_with("--cpus")
+                || a.starts_with("--memory")
+                || a.starts_with("--pids-limit")
+                || a.starts_with("--ulimit")),
+            "{:?}",
+            args
+        );
+        assert!(!args.iter().any(|a| a == "--interactive"), "{:?}", args);
+    }
+}
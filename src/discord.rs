@@ -1,23 +1,35 @@
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serenity::{
+    http::AttachmentType,
     model::{
         channel::Message,
         event::MessageUpdateEvent,
         gateway::{Activity, Ready},
-        id::MessageId,
+        id::{ChannelId, GuildId, MessageId, UserId},
     },
     prelude::{Context, EventHandler},
     utils::Color,
 };
 use sled::Tree;
-use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use unicase::Ascii;
 
 use crate::{
-    options_parser::parse_options,
-    runner::{DockerRunner, UnrecognizedContainer},
+    backend::Backend,
+    lang::Langs,
+    options_parser::{parse_options, take_run_options, RunOptions},
+    rate_limit::RateLimits,
+    runner::{OutputMode, Progress, Report, RunError, RunSpec, SessionRun, SourceFile},
+    scheduler::{QueueFull, Scheduler, Turn},
+    session::{CantStart, Session, SessionKey, Sessions},
 };
 
 #[derive(Debug)]
@@ -31,14 +43,14 @@ impl MessageIds {
 
     pub fn insert(&self, k: MessageId, v: MessageId) -> sled::Result<Option<MessageId>> {
         self.0
-            .insert(&k.as_u64().to_le_bytes(), &v.as_u64().to_le_bytes())
+            .insert(k.as_u64().to_le_bytes(), &v.as_u64().to_le_bytes())
             .map(|opt| {
                 opt.map(|ivec| MessageId(u64::from_le_bytes(ivec.as_ref().try_into().unwrap())))
             })
     }
 
     pub fn get(&self, k: MessageId) -> sled::Result<Option<MessageId>> {
-        self.0.get(&k.as_u64().to_le_bytes()).map(|opt| {
+        self.0.get(k.as_u64().to_le_bytes()).map(|opt| {
             opt.map(|ivec| MessageId(u64::from_le_bytes(ivec.as_ref().try_into().unwrap())))
         })
     }
@@ -46,41 +58,263 @@ impl MessageIds {
 
 // TODO: Do I want to react to message when I send them?
 
-#[derive(Debug)]
 pub struct Handler {
     pub language_text: Box<str>,
-    pub runner: DockerRunner,
+    pub langs: Langs,
+    pub backend: Arc<dyn Backend>,
+    pub scheduler: Scheduler,
+    pub rate_limits: RateLimits,
     pub message_ids: MessageIds,
+    pub sessions: Sessions,
+}
+
+impl fmt::Debug for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handler")
+            .field("backend", &self.backend)
+            .field("scheduler", &self.scheduler)
+            .field("rate_limits", &self.rate_limits)
+            .field("message_ids", &self.message_ids)
+            .field("sessions", &self.sessions)
+            .finish_non_exhaustive()
+    }
 }
 
 async fn should_run(_ctx: &Context, msg: &Message) -> bool {
     msg.content.contains("#!run")
 }
 
+/// Who sent a request and where, which decides their session and rate limits
+#[derive(Debug, Clone, Copy)]
+struct Origin {
+    user: UserId,
+    channel: ChannelId,
+    guild: Option<GuildId>,
+}
+
+impl Origin {
+    fn of(msg: &Message) -> Self {
+        Self {
+            user: msg.author.id,
+            channel: msg.channel_id,
+            guild: msg.guild_id,
+        }
+    }
+
+    /// Whose session their code would run in
+    fn session_key(self) -> SessionKey {
+        SessionKey {
+            user: self.user,
+            channel: self.channel,
+        }
+    }
+
+    /// Takes a token for something they asked for, or says why they can't have one
+    fn check_rate_limit(self, rate_limits: &RateLimits) -> Result<(), String> {
+        match rate_limits.check(self.user, self.channel, self.guild) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(limited)) => Err(limited.to_string()),
+            Err(err) => {
+                tracing::error!("Failed to check rate limits: {}", err);
+                Err("Something went wrong on my end. Please try again.".to_owned())
+            }
+        }
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
-struct RunMessage<'a> {
-    opts: &'a str,
+struct CodeBlock<'a> {
     lang: &'a str,
+    file: Option<&'a str>,
     code: &'a str,
 }
 
-fn parse_message(msg: &str) -> Option<RunMessage> {
-    // (?s) enables the 's' flag which lets . match '\n'
-    static CMD_RUN_ALL: Lazy<Regex> = Lazy::new(|| {
-        Regex::new(r"(?s)#!run\s+((?P<opts>.*)\s+)?```(?P<lang>\S*)\n(?P<code>.*)```").unwrap()
+#[derive(Debug, Eq, PartialEq)]
+struct RunMessage<'a> {
+    opts: &'a str,
+    // Never empty. The first block decides the language
+    blocks: Vec<CodeBlock<'a>>,
+    stdin: Option<&'a str>,
+}
+
+fn parse_message(msg: &str) -> Option<RunMessage<'_>> {
+    // (?s) enables the 's' flag which lets . match '\n'. The options can't contain backticks so
+    // that they don't swallow the code block itself.
+    static CMD_RUN: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?s)#!run\s+((?P<opts>[^`]*?)\s+)?```").unwrap());
+    // Code blocks may be annotated with a file name like ```c file=util.h
+    static CODE_BLOCK: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"(?s)```(?P<lang>\S*)(?:[ \t]+file=(?P<file>\S+))?[^\n]*\n(?P<code>.*?)```")
+            .unwrap()
     });
 
-    CMD_RUN_ALL.captures(msg).map(|caps| RunMessage {
-        opts: caps.name("opts").map(|s| s.as_str()).unwrap_or(""),
+    let caps = CMD_RUN.captures(msg)?;
+    let opts = caps.name("opts").map(|s| s.as_str()).unwrap_or("");
+    // Back up so that the code blocks start with their fence
+    let rest = &msg[caps.get(0).unwrap().end() - "```".len()..];
+
+    let mut blocks: Vec<CodeBlock> = Vec::new();
+    let mut stdin = None;
+    for caps in CODE_BLOCK.captures_iter(rest) {
+        let lang = caps.name("lang").unwrap().as_str();
+        let file = caps.name("file").map(|s| s.as_str());
+        let code = caps.name("code").unwrap().as_str();
+        // A block tagged as stdin holds what to feed the program rather than code
+        if matches!(lang, "stdin" | "input") {
+            stdin.get_or_insert(code);
+            continue;
+        }
+        // Once we have the file to run, other blocks are only ours if they're named. People
+        // paste example output and the like after their code
+        if file.is_none() && blocks.iter().any(|b| b.file.is_none()) {
+            continue;
+        }
+        blocks.push(CodeBlock { lang, file, code });
+    }
+    if blocks.is_empty() {
+        return None;
+    }
+
+    Some(RunMessage {
+        opts,
+        blocks,
+        stdin,
+    })
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum SessionCommand<'a> {
+    Start { lang: &'a str, opts: &'a str },
+    End,
+}
+
+fn parse_session_command(msg: &str) -> Option<SessionCommand<'_>> {
+    static CMD_SESSION: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(
+            r"(?s)\A#!session\s+(?:start\s+(?P<lang>\S+)(?:\s+(?P<opts>.*?))?|(?P<end>end))\s*\z",
+        )
+        .unwrap()
+    });
+
+    let caps = CMD_SESSION.captures(msg)?;
+    if caps.name("end").is_some() {
+        return Some(SessionCommand::End);
+    }
+    Some(SessionCommand::Start {
         lang: caps.name("lang").unwrap().as_str(),
-        code: caps.name("code").unwrap().as_str(),
+        opts: caps.name("opts").map(|s| s.as_str()).unwrap_or(""),
     })
 }
 
+/// Whether `path` is a plain relative path that stays within the working directory
+fn is_valid_file_name(path: &str) -> bool {
+    static FILE_NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"\A[\w.+-]+(/[\w.+-]+)*\z").unwrap());
+    FILE_NAME.is_match(path) && path.split('/').all(|part| part != "." && part != "..")
+}
+
+/// What to say in response to a run request
+#[derive(Debug, Default)]
+struct Reply {
+    content: String,
+    /// (file name, contents)
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Reply {
+    /// The rendered report, with the full output if it doesn't fit and whatever files the program
+    /// left. Discord shows attached images inline
+    fn from_report(report: &Report, mode: OutputMode) -> Self {
+        let mut files: Vec<_> = report
+            .attachment(mode)
+            .map(|output| ("output.txt".to_owned(), output))
+            .into_iter()
+            .collect();
+        files.extend(report.files().files.iter().map(|file| {
+            // Attachments can't be in directories
+            (file.path.replace('/', "_"), file.contents.clone())
+        }));
+        Self {
+            content: report.render(mode).to_string(),
+            files,
+        }
+    }
+}
+
+impl From<String> for Reply {
+    fn from(content: String) -> Self {
+        Self {
+            content,
+            files: Vec::new(),
+        }
+    }
+}
+
+/// Sends `reply` in response to `reference` without pinging anyone
+async fn send_reply(
+    ctx: &Context,
+    channel_id: ChannelId,
+    reference: MessageId,
+    reply: &Reply,
+) -> serenity::Result<Message> {
+    channel_id
+        .send_message(ctx, |m| {
+            m.reference_message((channel_id, reference))
+                .allowed_mentions(|f| f.replied_user(false))
+                .content(&reply.content)
+                .add_files(
+                    reply
+                        .files
+                        .iter()
+                        .map(|(name, data)| AttachmentType::Bytes {
+                            data: data.as_slice().into(),
+                            filename: name.clone(),
+                        }),
+                )
+        })
+        .await
+}
+
+/// Replaces the contents of our reply. Discord doesn't let us attach files to an edited message,
+/// so any files go in a follow-up reply
+async fn edit_reply(
+    ctx: &Context,
+    channel_id: ChannelId,
+    reply_id: MessageId,
+    reply: &Reply,
+) -> serenity::Result<()> {
+    channel_id
+        .edit_message(ctx, reply_id, |builder| builder.content(&reply.content))
+        .await?;
+    if !reply.files.is_empty() {
+        send_reply(
+            ctx,
+            channel_id,
+            reply_id,
+            &Reply {
+                content: String::new(),
+                files: reply.files.clone(),
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
 // XXX: Ideally this would use generators rather than a channel...
-async fn try_run_raw(runner: &DockerRunner, msg: &str, tx: Sender<String>) {
+#[allow(clippy::too_many_arguments)]
+async fn try_run_raw(
+    langs: &Langs,
+    backend: &dyn Backend,
+    scheduler: &Scheduler,
+    sessions: &Sessions,
+    rate_limits: &RateLimits,
+    origin: Origin,
+    msg: &str,
+    tx: Sender<Reply>,
+) {
+    let key = origin.session_key();
     macro_rules! send {
-        ($($arg:tt)*) => ( tx.send(format!($($arg)*)).await.unwrap() )
+        ($($arg:tt)*) => ( tx.send(Reply::from(format!($($arg)*))).await.unwrap() )
     }
     macro_rules! bail {
         ($($arg:tt)*) => ( return send!($($arg)*) )
@@ -98,51 +332,373 @@ print('Hello World')
 \`\`\`"
         ),
     };
-    if run.lang.is_empty() {
+    let main = &run.blocks[0];
+    if main.lang.is_empty() {
         bail!(
             r"I noticed you sent a code block but didn't include a language tag, so I don't know how to run it. The language goes immediately after the \`\`\` like so
 
 \`\`\`your-language-here
 {code}\`\`\`",
-            code = run.code
+            code = main.code
         );
     }
-    let opts = match parse_options(run.opts) {
+    let mut opts = match parse_options(run.opts) {
         Ok(opts) => opts,
         // TODO: Improve error messages
         Err(err) => bail!("{}", err),
     };
+    let run_opts = match take_run_options(&mut opts) {
+        Ok(run_opts) => run_opts,
+        Err(err) => bail!("{}", err),
+    };
 
     tracing::debug!("{:?}", run);
-    let lang_ref = match runner.get_lang_by_code(run.lang) {
+    let lang_ref = match langs.get(&Ascii::new(main.lang)) {
         Some(lang) => lang,
         // TODO: Get suggestions using strsim
         None => bail!(
             "I'm sorry. I don't know how to run `{}` code snippets.",
-            run.lang,
+            main.lang,
         ),
     };
 
+    // Whatever's left is for the language, which a session has already been started with
+    let mut lang_opts: Vec<&str> = opts.keys().copied().collect();
+    lang_opts.sort_unstable();
     let run_spec = match lang_ref.run_spec(opts) {
         Ok(run_spec) => run_spec,
         Err(err) => bail!("{}", err),
     };
-    match runner.run_code(&run_spec, run.code).await {
-        Ok(output) => send!("{}", output),
-        Err(err) => match err.downcast_ref::<UnrecognizedContainer>() {
-            Some(_) => {
-                send!("Building container. Please be patient. This may take awhile.");
-                if let Err(err) = runner.build(&run_spec).await {
-                    bail!("{}", err);
+
+    // Code blocks without a file name are the file we run
+    let mut files: Vec<SourceFile> = Vec::with_capacity(run.blocks.len());
+    for block in &run.blocks {
+        let path = match block.file {
+            Some(path) if is_valid_file_name(path) => path,
+            Some(path) => bail!(
+                "`{}` isn't a file name I can use. File names must be relative paths without `.` or `..`.",
+                path,
+            ),
+            None => run_spec.code_path,
+        };
+        if files.iter().any(|f| f.path == path) {
+            bail!(
+                "You gave me more than one file named `{}`. Remember that the code block without a `file=` name is saved as `{}`.",
+                path,
+                run_spec.code_path,
+            );
+        }
+        files.push(SourceFile {
+            path,
+            contents: block.code,
+        });
+    }
+    if !files.iter().any(|f| f.path == run_spec.code_path) {
+        bail!(
+            "I run `{}` for {} code, but none of your code blocks is named that. Leave the `file=` off of the code block you want me to run.",
+            run_spec.code_path,
+            lang_ref,
+        );
+    }
+
+    // Code in the language of the sender's session runs there instead
+    let session = match sessions.get(key) {
+        Ok(session) => session.filter(|session| session.lang == lang_ref.to_string()),
+        Err(err) => {
+            tracing::error!("Failed to look up session: {}", err);
+            None
+        }
+    };
+    if session.is_some() {
+        if files.len() > 1 {
+            bail!("Sessions run one code block at a time, so I can't use `file=` there.");
+        }
+        if !run_opts.args.is_empty() || !run_opts.env.is_empty() {
+            bail!("A session's program is already running, so `args` and `env` can't be changed.");
+        }
+        if !lang_opts.is_empty() {
+            bail!(
+                "A session's program is already running, so `{}` can't be changed. Set {} options with `#!session start` instead.",
+                lang_opts.join("`, `"),
+                lang_ref,
+            );
+        }
+    }
+
+    // Invalid requests are turned away above so they don't use up tokens or take up space in line
+    if let Err(limited) = origin.check_rate_limit(rate_limits) {
+        bail!("{}", limited);
+    }
+    let mut ticket = match scheduler.enqueue() {
+        Ok(ticket) => ticket,
+        Err(QueueFull) => bail!("I'm running too much code right now. Please try again in a bit."),
+    };
+    let _permit = loop {
+        match ticket.advance().await {
+            Turn::Ready(permit) => break permit,
+            Turn::Queued(position) => send!(
+                "Queued, position {}. I'll run your code once it's your turn.",
+                position
+            ),
+        }
+    };
+
+    // The details of what went wrong are for us, not whoever sent the code
+    macro_rules! bail_run_error {
+        ($err:expr) => {{
+            let err = $err;
+            tracing::error!("Failed to run {}: {}", run_spec.image_name, err);
+            bail!("{}", err.user_message())
+        }};
+    }
+    if let Some(session) = session {
+        let forget = || {
+            if let Err(err) = sessions.forget(key, &session.id) {
+                tracing::error!("Failed to forget session {}: {}", session.id, err);
+            }
+        };
+        // It may have been idle for too long while we waited our turn
+        match sessions.touch(key, &session.id) {
+            Ok(true) => {}
+            Ok(false) => bail!(
+                "Your {} session ended while your code was waiting to run. Send `#!session start {}` to start a new one.",
+                session.lang,
+                main.lang,
+            ),
+            Err(err) => tracing::error!("Failed to update session {}: {}", session.id, err),
+        }
+        match run_in_session_with_progress(backend, &session, main.code, run.stdin, &run_opts, &tx)
+            .await
+        {
+            Ok(SessionRun { output, ended }) => {
+                let report = Report {
+                    compile: None,
+                    run: Some(output),
+                };
+                let mut reply = Reply::from_report(&report, run_opts.output);
+                if ended {
+                    forget();
+                    reply.content.push_str("\n*This ended your session.*");
+                } else if let Err(err) = sessions.touch(key, &session.id) {
+                    // Idle means since the last run finished, not since it started
+                    tracing::error!("Failed to update session {}: {}", session.id, err);
                 }
-                match runner.run_code(&run_spec, run.code).await {
-                    Ok(output) => send!("{}", output),
-                    Err(err) => bail!("{}", err),
+                tx.send(reply).await.unwrap();
+            }
+            Err(err) => {
+                if let RunError::SessionEnded = err {
+                    forget();
                 }
+                bail_run_error!(err);
             }
-            None => bail!("{}", err),
+        }
+        return;
+    }
+    match backend.image_exists(&run_spec).await {
+        Ok(true) => {}
+        Ok(false) => {
+            send!("Building container. Please be patient. This may take awhile.");
+            if let Err(err) = backend.build(&run_spec).await {
+                bail_run_error!(err);
+            }
+        }
+        Err(err) => bail_run_error!(err),
+    }
+    match run_with_progress(backend, &run_spec, &files, run.stdin, &run_opts, &tx).await {
+        Ok(report) => tx
+            .send(Reply::from_report(&report, run_opts.output))
+            .await
+            .unwrap(),
+        Err(err) => bail_run_error!(err),
+    }
+}
+
+/// Discord rate limits message edits, so we don't show progress any more often than this
+const EDIT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Runs the code, sending what it's printed so far while it runs
+async fn run_with_progress(
+    backend: &dyn Backend,
+    spec: &RunSpec,
+    files: &[SourceFile<'_>],
+    stdin: Option<&str>,
+    opts: &RunOptions,
+    tx: &Sender<Reply>,
+) -> Result<Report, RunError> {
+    let (progress_tx, progress_rx) = mpsc::channel(1);
+    let run = async {
+        // Dropping the sender once we're done lets `forward_progress` finish
+        let progress_tx = progress_tx;
+        backend
+            .run_code(spec, files, stdin, opts, Some(&progress_tx))
+            .await
+    };
+    tokio::join!(run, forward_progress(progress_rx, opts, tx)).0
+}
+
+/// Like `run_with_progress`, but for a snippet in `session`
+async fn run_in_session_with_progress(
+    backend: &dyn Backend,
+    session: &Session,
+    code: &str,
+    stdin: Option<&str>,
+    opts: &RunOptions,
+    tx: &Sender<Reply>,
+) -> Result<SessionRun, RunError> {
+    let (progress_tx, progress_rx) = mpsc::channel(1);
+    let run = async {
+        let progress_tx = progress_tx;
+        backend
+            .run_in_session(&session.id, code, stdin, Some(&progress_tx))
+            .await
+    };
+    tokio::join!(run, forward_progress(progress_rx, opts, tx)).0
+}
+
+/// Sends what's been printed so far as it comes in, until `progress` closes
+async fn forward_progress(mut progress: Receiver<Progress>, opts: &RunOptions, tx: &Sender<Reply>) {
+    let mut last_edit: Option<Instant> = None;
+    while let Some(progress) = progress.recv().await {
+        if last_edit.is_none_or(|t| t.elapsed() >= EDIT_INTERVAL) {
+            tx.send(Reply::from(progress.render(opts.output)))
+                .await
+                .unwrap();
+            last_edit = Some(Instant::now());
+        }
+    }
+}
+
+/// Starts or ends the sender's session
+async fn try_session(
+    langs: &Langs,
+    backend: &dyn Backend,
+    sessions: &Sessions,
+    rate_limits: &RateLimits,
+    origin: Origin,
+    cmd: SessionCommand<'_>,
+    tx: Sender<Reply>,
+) {
+    let key = origin.session_key();
+    macro_rules! send {
+        ($($arg:tt)*) => ( tx.send(Reply::from(format!($($arg)*))).await.unwrap() )
+    }
+    macro_rules! bail {
+        ($($arg:tt)*) => ( return send!($($arg)*) )
+    }
+    macro_rules! bail_db_error {
+        ($err:expr) => {{
+            tracing::error!("Failed to access sessions: {}", $err);
+            bail!("Something went wrong on my end. Please try again.")
+        }};
+    }
+    macro_rules! bail_run_error {
+        ($err:expr) => {{
+            let err = $err;
+            tracing::error!("Failed to start a session: {}", err);
+            bail!("{}", err.user_message())
+        }};
+    }
+
+    let (lang, opts) = match cmd {
+        SessionCommand::Start { lang, opts } => (lang, opts),
+        SessionCommand::End => match sessions.remove(key) {
+            Ok(Some(session)) => {
+                if let Err(err) = backend.end_session(&session.id).await {
+                    tracing::error!("Failed to end session {}: {}", session.id, err);
+                }
+                bail!("Ended your {} session.", session.lang);
+            }
+            Ok(None) => bail!("You don't have a session in this channel."),
+            Err(err) => bail_db_error!(err),
         },
+    };
+    match sessions.check_start(key) {
+        Ok(Ok(())) => {}
+        Ok(Err(CantStart::Exists(session))) => bail!(
+            "You already have a {} session in this channel. Send `#!session end` to end it first.",
+            session.lang,
+        ),
+        Ok(Err(CantStart::Full)) => {
+            bail!("There are too many sessions running right now. Please try again later.")
+        }
+        Err(err) => bail_db_error!(err),
+    }
+    let lang_ref = match langs.get(&Ascii::new(lang)) {
+        Some(lang) => lang,
+        None => bail!(
+            "I'm sorry. I don't know how to run `{}` code snippets.",
+            lang
+        ),
+    };
+    let cmd = match lang_ref.session_cmd() {
+        Some(cmd) => cmd,
+        None => bail!("I'm sorry. I can't keep {} sessions.", lang_ref),
+    };
+    let run_spec = match parse_options(opts)
+        .map_err(|err| err.to_string())
+        .and_then(|opts| lang_ref.run_spec(opts).map_err(|err| err.to_string()))
+    {
+        Ok(run_spec) => run_spec,
+        Err(err) => bail!("{}", err),
+    };
+    // Only starting one costs anything
+    if let Err(limited) = origin.check_rate_limit(rate_limits) {
+        bail!("{}", limited);
+    }
+
+    match backend.image_exists(&run_spec).await {
+        Ok(true) => {}
+        Ok(false) => {
+            send!("Building container. Please be patient. This may take awhile.");
+            if let Err(err) = backend.build(&run_spec).await {
+                bail_run_error!(err);
+            }
+        }
+        Err(err) => bail_run_error!(err),
+    }
+    let id = match backend.start_session(&run_spec, &cmd).await {
+        Ok(id) => id,
+        Err(err) => bail_run_error!(err),
+    };
+    let session = Session::new(id, lang_ref.to_string());
+    let failure = match sessions.insert(key, &session) {
+        Ok(Ok(())) => None,
+        // Another start got there first
+        Ok(Err(CantStart::Exists(other))) => Some(format!(
+            "You already have a {} session in this channel. Send `#!session end` to end it first.",
+            other.lang,
+        )),
+        Ok(Err(CantStart::Full)) => Some(
+            "There are too many sessions running right now. Please try again later.".to_owned(),
+        ),
+        Err(err) => {
+            tracing::error!("Failed to access sessions: {}", err);
+            Some("Something went wrong on my end. Please try again.".to_owned())
+        }
+    };
+    if let Some(failure) = failure {
+        // Nothing else knows about it, so nothing else would end it
+        if let Err(err) = backend.end_session(&session.id).await {
+            tracing::error!("Failed to end session {}: {}", session.id, err);
+        }
+        bail!("{}", failure);
     }
+
+    let idle_secs = sessions.conf().idle_secs;
+    let expiry = if idle_secs > 0 {
+        format!(
+            " or don't run anything for {} minutes",
+            idle_secs.div_ceil(60)
+        )
+    } else {
+        String::new()
+    };
+    send!(
+        "Started a {} session. Your {} `#!run`s in this channel will share it until you send `#!session end`{}.",
+        lang_ref,
+        lang_ref,
+        expiry,
+    );
 }
 
 #[serenity::async_trait]
@@ -184,7 +740,6 @@ impl EventHandler for Handler {
             }
         };
 
-        let runner = &self.runner;
         let (tx, mut rx) = mpsc::channel(2);
         msg.channel_id
             .edit_message(&ctx, reply_id, |builder| {
@@ -194,16 +749,22 @@ impl EventHandler for Handler {
             .expect("failed to edit message");
         tokio::join!(
             async {
-                try_run_raw(runner, &msg.content, tx).await;
+                try_run_raw(
+                    &self.langs,
+                    &*self.backend,
+                    &self.scheduler,
+                    &self.sessions,
+                    &self.rate_limits,
+                    Origin::of(&msg),
+                    &msg.content,
+                    tx,
+                )
+                .await;
             },
             async {
-                while let Some(ref body) = rx.recv().await {
-                    match msg
-                        .channel_id
-                        .edit_message(&ctx, reply_id, |builder| builder.content(body))
-                        .await
-                    {
-                        Ok(_) => {}
+                while let Some(ref reply) = rx.recv().await {
+                    match edit_reply(&ctx, msg.channel_id, reply_id, reply).await {
+                        Ok(()) => {}
                         Err(err) => {
                             msg.channel_id
                                 .edit_message(&ctx, reply_id, |builder| builder.content(err))
@@ -227,7 +788,17 @@ impl EventHandler for Handler {
             // We extract this because otherwise rustfmt falis
             const HELP: &str = r#"I know how to run a variety of languages. All you have to do to ask me to run a block of code is to include the #!run command at the end of the message followed by the code block you want to run.
 
-Make sure to include a language right after backticks (\`\`\`) or else I won't know how to run your code!"#;
+Make sure to include a language right after backticks (\`\`\`) or else I won't know how to run your code!
+
+If your program reads from stdin, put its input in a second code block tagged `stdin` right after your code.
+
+You can send more than one file by naming the extra code blocks like \`\`\`c file=util.h. The code block without a name is the one I run.
+
+Every language accepts `args="..."` to pass command line arguments and `env.NAME=value` to set environment variables, like `#!run args="-n 3" env.DEBUG=1`. Add `output=split` to see stdout and stderr separately, `output=stdout` to hide stderr, or `output=hex` to see stdout as a hexdump.
+
+Files your program writes to the `out` directory next to it are attached to my reply, so you can save plots with `plt.savefig("out/plot.png")`.
+
+Send `#!session start python` to keep an interpreter running for you in a channel, like a notebook. Your Python code blocks there run in it, keeping whatever earlier ones defined, until you send `#!session end`."#;
             const EXAMPLE: &str = r#"You can write something here to explain your code if you want #!run \`\`\`python
 print("Hello, World!")
 \`\`\`"#;
@@ -247,25 +818,66 @@ print("Hello, World!")
                 })
                 .await
                 .expect("failed to send help message");
+        } else if msg.content.starts_with("#!session") {
+            let cmd = match parse_session_command(&msg.content) {
+                Some(cmd) => cmd,
+                None => {
+                    msg.reply(&ctx, "Use `#!session start <language>` or `#!session end`.")
+                        .await
+                        .expect("failed to reply");
+                    return;
+                }
+            };
+            let (tx, mut rx) = mpsc::channel(2);
+            tokio::join!(
+                try_session(
+                    &self.langs,
+                    &*self.backend,
+                    &self.sessions,
+                    &self.rate_limits,
+                    Origin::of(&msg),
+                    cmd,
+                    tx,
+                ),
+                async {
+                    let first = rx.recv().await.expect("at least one message");
+                    let reply = send_reply(&ctx, msg.channel_id, msg.id, &first)
+                        .await
+                        .expect("failed to reply to message");
+                    while let Some(ref body) = rx.recv().await {
+                        edit_reply(&ctx, msg.channel_id, reply.id, body)
+                            .await
+                            .expect("failed to edit message");
+                    }
+                }
+            );
         } else if should_run(&ctx, &msg).await {
-            let runner = &self.runner;
             let (tx, mut rx) = mpsc::channel(2);
             tokio::join!(
                 async {
-                    try_run_raw(runner, &msg.content, tx).await;
+                    try_run_raw(
+                        &self.langs,
+                        &*self.backend,
+                        &self.scheduler,
+                        &self.sessions,
+                        &self.rate_limits,
+                        Origin::of(&msg),
+                        &msg.content,
+                        tx,
+                    )
+                    .await;
                 },
                 async {
-                    let body = rx.recv().await.expect("at least one message");
-                    let mut reply = msg
-                        .reply(&ctx, body)
+                    let first = rx.recv().await.expect("at least one message");
+                    let mut reply = send_reply(&ctx, msg.channel_id, msg.id, &first)
                         .await
                         .expect("failed to reply to message");
                     if self.message_ids.insert(msg.id, reply.id).unwrap().is_some() {
                         panic!("colliding message ids");
                     }
                     while let Some(ref body) = rx.recv().await {
-                        match reply.edit(&ctx, |builder| builder.content(body)).await {
-                            Ok(_) => {}
+                        match edit_reply(&ctx, msg.channel_id, reply.id, body).await {
+                            Ok(()) => {}
                             Err(err) => {
                                 reply
                                     .edit(&ctx, |builder| builder.content(err))
@@ -296,6 +908,15 @@ print("Hello, World!")
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serenity::model::id::UserId;
+
+    use crate::{
+        backend::FakeBackend,
+        lang::LangRef,
+        rate_limit::{Bucket, RateLimitConfig},
+        runner::{Output, OutputFile, OutputFiles, Termination},
+        session::SessionConfig,
+    };
 
     #[test]
     fn test_parse_empty() {
@@ -315,9 +936,13 @@ mod tests {
         assert_eq!(
             parse_message("#!run ```py\nprint('Hello, World!')\n```"),
             Some(RunMessage {
-                lang: "py",
                 opts: "",
-                code: "print('Hello, World!')\n",
+                blocks: vec![CodeBlock {
+                    lang: "py",
+                    file: None,
+                    code: "print('Hello, World!')\n",
+                }],
+                stdin: None,
             }),
         );
     }
@@ -327,9 +952,13 @@ mod tests {
         assert_eq!(
             parse_message("#!run version=3.8 ```py\nprint('Hello, World!')\n```"),
             Some(RunMessage {
-                lang: "py",
                 opts: "version=3.8",
-                code: "print('Hello, World!')\n",
+                blocks: vec![CodeBlock {
+                    lang: "py",
+                    file: None,
+                    code: "print('Hello, World!')\n",
+                }],
+                stdin: None,
             }),
         );
     }
@@ -347,9 +976,13 @@ mod tests {
         assert_eq!(
             parse_message("Some exposition\n#!run ```py\nprint('Hello, World!')\n```"),
             Some(RunMessage {
-                lang: "py",
                 opts: "",
-                code: "print('Hello, World!')\n",
+                blocks: vec![CodeBlock {
+                    lang: "py",
+                    file: None,
+                    code: "print('Hello, World!')\n",
+                }],
+                stdin: None,
             }),
         );
     }
@@ -359,9 +992,13 @@ mod tests {
         assert_eq!(
             parse_message("Some exposition #!run ```py\nprint('Hello, World!')\n```"),
             Some(RunMessage {
-                lang: "py",
                 opts: "",
-                code: "print('Hello, World!')\n",
+                blocks: vec![CodeBlock {
+                    lang: "py",
+                    file: None,
+                    code: "print('Hello, World!')\n",
+                }],
+                stdin: None,
             }),
         );
     }
@@ -371,10 +1008,482 @@ mod tests {
         assert_eq!(
             parse_message("#!run ```sh\necho I 𝓵𝓸𝓿𝓮 unicode\n```"),
             Some(RunMessage {
-                lang: "sh",
                 opts: "",
-                code: "echo I 𝓵𝓸𝓿𝓮 unicode\n",
+                blocks: vec![CodeBlock {
+                    lang: "sh",
+                    file: None,
+                    code: "echo I 𝓵𝓸𝓿𝓮 unicode\n",
+                }],
+                stdin: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_stdin() {
+        assert_eq!(
+            parse_message("#!run ```py\nprint(input())\n```\n```stdin\nHello, World!\n```"),
+            Some(RunMessage {
+                opts: "",
+                blocks: vec![CodeBlock {
+                    lang: "py",
+                    file: None,
+                    code: "print(input())\n",
+                }],
+                stdin: Some("Hello, World!\n"),
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(
+            parse_message("#!run version=3.8 ```py\nprint(input())\n``` ```input\n1 2 3\n```"),
+            Some(RunMessage {
+                opts: "version=3.8",
+                blocks: vec![CodeBlock {
+                    lang: "py",
+                    file: None,
+                    code: "print(input())\n",
+                }],
+                stdin: Some("1 2 3\n"),
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_untagged_second_block() {
+        // Only blocks tagged as stdin are fed to the program, and unnamed ones are left alone
+        assert_eq!(
+            parse_message("#!run ```py\nprint(input())\n```\n```\nHello, World!\n```"),
+            Some(RunMessage {
+                opts: "",
+                blocks: vec![CodeBlock {
+                    lang: "py",
+                    file: None,
+                    code: "print(input())\n",
+                }],
+                stdin: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_files() {
+        assert_eq!(
+            parse_message(
+                "#!run ```c file=util.h\nint f();\n```\nand\n```c\n#include \"util.h\"\n```"
+            ),
+            Some(RunMessage {
+                opts: "",
+                blocks: vec![
+                    CodeBlock {
+                        lang: "c",
+                        file: Some("util.h"),
+                        code: "int f();\n",
+                    },
+                    CodeBlock {
+                        lang: "c",
+                        file: None,
+                        code: "#include \"util.h\"\n",
+                    },
+                ],
+                stdin: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_files_after_main() {
+        assert_eq!(
+            parse_message(
+                "#!run ```py\nimport util\n```\n```\nexpected output\n```\n```py file=util.py\nx = 1\n```"
+            ),
+            Some(RunMessage {
+                opts: "",
+                blocks: vec![
+                    CodeBlock {
+                        lang: "py",
+                        file: None,
+                        code: "import util\n",
+                    },
+                    CodeBlock {
+                        lang: "py",
+                        file: Some("util.py"),
+                        code: "x = 1\n",
+                    },
+                ],
+                stdin: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_valid_file_name() {
+        assert!(is_valid_file_name("util.h"));
+        assert!(is_valid_file_name("pkg/__init__.py"));
+        assert!(!is_valid_file_name(""));
+        assert!(!is_valid_file_name("/etc/passwd"));
+        assert!(!is_valid_file_name("../main.c"));
+        assert!(!is_valid_file_name("pkg//main.c"));
+        assert!(!is_valid_file_name("my file.c"));
+    }
+
+    #[test]
+    fn test_reply_files() {
+        let report = Report {
+            compile: None,
+            run: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![],
+                usage: None,
+                files: OutputFiles {
+                    files: vec![
+                        OutputFile {
+                            path: "plot.png".to_owned(),
+                            contents: vec![1],
+                        },
+                        OutputFile {
+                            path: "data/out.csv".to_owned(),
+                            contents: vec![2],
+                        },
+                    ],
+                    truncated: false,
+                },
+            }),
+        };
+        let reply = Reply::from_report(&report, OutputMode::default());
+        assert_eq!(reply.content, "```\n```");
+        assert_eq!(
+            reply.files,
+            [
+                ("plot.png".to_owned(), vec![1]),
+                ("data_out.csv".to_owned(), vec![2]),
+            ]
+        );
+    }
+
+    fn test_langs() -> Langs {
+        inventory::iter::<LangRef>
+            .into_iter()
+            .flat_map(|&lang| lang.codes().iter().map(move |&code| (code, lang)))
+            .collect()
+    }
+
+    const KEY: SessionKey = SessionKey {
+        user: UserId(1),
+        channel: ChannelId(2),
+    };
+
+    const ORIGIN: Origin = Origin {
+        user: KEY.user,
+        channel: KEY.channel,
+        guild: None,
+    };
+
+    fn test_sessions() -> Sessions {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Sessions::new(db.open_tree("sessions").unwrap(), SessionConfig::default())
+    }
+
+    /// Runs `msg` like a Discord message would, returning everything we replied with
+    async fn run(backend: &FakeBackend, scheduler: &Scheduler, msg: &str) -> Vec<String> {
+        run_as(backend, scheduler, &test_sessions(), msg).await
+    }
+
+    fn test_rate_limits(conf: RateLimitConfig) -> RateLimits {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        RateLimits::new(db.open_tree("rate_limits").unwrap(), conf)
+    }
+
+    /// Like `run`, but from the sender of `KEY` with `sessions`
+    async fn run_as(
+        backend: &FakeBackend,
+        scheduler: &Scheduler,
+        sessions: &Sessions,
+        msg: &str,
+    ) -> Vec<String> {
+        let rate_limits = test_rate_limits(Default::default());
+        run_limited(backend, scheduler, sessions, &rate_limits, msg).await
+    }
+
+    async fn run_limited(
+        backend: &FakeBackend,
+        scheduler: &Scheduler,
+        sessions: &Sessions,
+        rate_limits: &RateLimits,
+        msg: &str,
+    ) -> Vec<String> {
+        let (tx, rx) = mpsc::channel(16);
+        let langs = test_langs();
+        try_run_raw(
+            &langs,
+            backend,
+            scheduler,
+            sessions,
+            rate_limits,
+            ORIGIN,
+            msg,
+            tx,
+        )
+        .await;
+        collect(rx).await
+    }
+
+    async fn session(backend: &FakeBackend, sessions: &Sessions, msg: &str) -> Vec<String> {
+        let cmd = parse_session_command(msg).unwrap();
+        let (tx, rx) = mpsc::channel(16);
+        let rate_limits = test_rate_limits(Default::default());
+        try_session(
+            &test_langs(),
+            backend,
+            sessions,
+            &rate_limits,
+            ORIGIN,
+            cmd,
+            tx,
+        )
+        .await;
+        collect(rx).await
+    }
+
+    async fn collect(mut rx: Receiver<Reply>) -> Vec<String> {
+        let mut replies = Vec::new();
+        while let Some(reply) = rx.recv().await {
+            replies.push(reply.content);
+        }
+        replies
+    }
+
+    const BUILDING: &str = "Building container. Please be patient. This may take awhile.";
+
+    #[tokio::test]
+    async fn test_rate_limit_after_parsing() {
+        let backend = FakeBackend::default();
+        let scheduler = Scheduler::new(1, 1);
+        let sessions = test_sessions();
+        let rate_limits = test_rate_limits(RateLimitConfig {
+            user: Some(Bucket {
+                capacity: 1.0,
+                refill_secs: 3600.0,
+            }),
+            ..Default::default()
+        });
+        // Requests we can't make sense of don't cost anything
+        for msg in ["#!run hi", "#!run ```cobol\nDISPLAY 'HI'.\n```"] {
+            let replies = run_limited(&backend, &scheduler, &sessions, &rate_limits, msg).await;
+            assert!(!replies[0].contains("too often"), "{:?}", replies);
+        }
+        let msg = "#!run ```py\npass\n```";
+        let replies = run_limited(&backend, &scheduler, &sessions, &rate_limits, msg).await;
+        assert_eq!(replies.last().unwrap(), "```\nrun.py\n```");
+        let replies = run_limited(&backend, &scheduler, &sessions, &rate_limits, msg).await;
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].starts_with("You're running code too often."));
+    }
+
+    #[tokio::test]
+    async fn test_run_builds_once() {
+        let backend = FakeBackend::default();
+        let scheduler = Scheduler::new(1, 1);
+        let msg = "#!run ```py\nprint(input())\n```\n```stdin\nhi\n```";
+        assert_eq!(
+            run(&backend, &scheduler, msg).await,
+            vec![BUILDING, "```\nrun.py\nhi\n```"],
+        );
+        assert_eq!(
+            run(&backend, &scheduler, msg).await,
+            vec!["```\nrun.py\nhi\n```"],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_options() {
+        let backend = FakeBackend::default();
+        let scheduler = Scheduler::new(1, 1);
+        let msg = "#!run args=\"a b\" output=stdout ```py\npass\n```";
+        assert_eq!(
+            run(&backend, &scheduler, msg).await,
+            vec![BUILDING, "```\nrun.py\n```"],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_build_error() {
+        let backend = FakeBackend {
+            build_error: Some("no space left on device"),
+            ..Default::default()
+        };
+        let scheduler = Scheduler::new(1, 1);
+        assert_eq!(
+            run(&backend, &scheduler, "#!run ```py\npass\n```").await,
+            vec![
+                BUILDING,
+                "I couldn't build the container for this language. Please let my maintainers know."
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_error() {
+        let backend = FakeBackend {
+            run_error: Some("container exploded"),
+            ..Default::default()
+        };
+        let scheduler = Scheduler::new(1, 1);
+        assert_eq!(
+            run(&backend, &scheduler, "#!run ```py\npass\n```").await,
+            vec![
+                BUILDING,
+                "I can't reach my containers right now. Please try again in a bit."
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_unknown_language() {
+        let backend = FakeBackend::default();
+        let scheduler = Scheduler::new(1, 1);
+        assert_eq!(
+            run(&backend, &scheduler, "#!run ```brainfuck\n+\n```").await,
+            vec!["I'm sorry. I don't know how to run `brainfuck` code snippets."],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_queue_full() {
+        let backend = FakeBackend::default();
+        let scheduler = Scheduler::new(0, 0);
+        assert_eq!(
+            run(&backend, &scheduler, "#!run ```py\npass\n```").await,
+            vec!["I'm running too much code right now. Please try again in a bit."],
+        );
+    }
+
+    #[test]
+    fn test_parse_session_command() {
+        assert_eq!(
+            parse_session_command("#!session start python"),
+            Some(SessionCommand::Start {
+                lang: "python",
+                opts: "",
             }),
         );
+        assert_eq!(
+            parse_session_command("#!session start py version=3.8\n"),
+            Some(SessionCommand::Start {
+                lang: "py",
+                opts: "version=3.8",
+            }),
+        );
+        assert_eq!(
+            parse_session_command("#!session end"),
+            Some(SessionCommand::End)
+        );
+        assert_eq!(parse_session_command("#!session start"), None);
+        assert_eq!(parse_session_command("#!session stop"), None);
+        assert_eq!(parse_session_command("#!sessions end"), None);
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let backend = FakeBackend::default();
+        let scheduler = Scheduler::new(1, 1);
+        let sessions = test_sessions();
+        const STARTED: &str = "Started a Python session. Your Python `#!run`s in this channel will share it until you send `#!session end` or don't run anything for 10 minutes.";
+        assert_eq!(
+            session(&backend, &sessions, "#!session start python").await,
+            vec![BUILDING, STARTED],
+        );
+        assert_eq!(
+            session(&backend, &sessions, "#!session start py").await,
+            vec!["You already have a Python session in this channel. Send `#!session end` to end it first."],
+        );
+
+        // Every snippet sees the ones before it
+        assert_eq!(
+            run_as(&backend, &scheduler, &sessions, "#!run ```py\nx = 1\n```").await,
+            vec!["```\nx = 1\n```"],
+        );
+        assert_eq!(
+            run_as(
+                &backend,
+                &scheduler,
+                &sessions,
+                "#!run ```py\nprint(x)\n```"
+            )
+            .await,
+            vec!["```\nx = 1\nprint(x)\n```"],
+        );
+        // Other languages run on their own
+        assert_eq!(
+            run_as(&backend, &scheduler, &sessions, "#!run ```sh\necho\n```").await,
+            vec![BUILDING, "```\nrun.sh\n```"],
+        );
+        assert_eq!(
+            run_as(
+                &backend,
+                &scheduler,
+                &sessions,
+                "#!run args=1 ```py\npass\n```"
+            )
+            .await,
+            vec!["A session's program is already running, so `args` and `env` can't be changed."],
+        );
+        assert_eq!(
+            run_as(
+                &backend,
+                &scheduler,
+                &sessions,
+                "#!run version=3.8 ```py\npass\n```"
+            )
+            .await,
+            vec!["A session's program is already running, so `version` can't be changed. Set Python options with `#!session start` instead."],
+        );
+
+        assert_eq!(
+            session(&backend, &sessions, "#!session end").await,
+            vec!["Ended your Python session."],
+        );
+        assert!(backend.sessions.lock().unwrap().is_empty());
+        assert_eq!(
+            session(&backend, &sessions, "#!session end").await,
+            vec!["You don't have a session in this channel."],
+        );
+        assert_eq!(
+            run_as(&backend, &scheduler, &sessions, "#!run ```py\npass\n```").await,
+            vec!["```\nrun.py\n```"],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_ended() {
+        let backend = FakeBackend::default();
+        let scheduler = Scheduler::new(1, 1);
+        let sessions = test_sessions();
+        session(&backend, &sessions, "#!session start python").await;
+        // Like when the container was killed
+        backend.sessions.lock().unwrap().clear();
+        assert_eq!(
+            run_as(&backend, &scheduler, &sessions, "#!run ```py\npass\n```").await,
+            vec!["Your session isn't running anymore. Start a new one with `#!session start`."],
+        );
+        assert_eq!(sessions.get(KEY).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_unsupported() {
+        let backend = FakeBackend::default();
+        let sessions = test_sessions();
+        assert_eq!(
+            session(&backend, &sessions, "#!session start sh").await,
+            vec!["I'm sorry. I can't keep Sh sessions."],
+        );
+        assert_eq!(
+            session(&backend, &sessions, "#!session start cobol").await,
+            vec!["I'm sorry. I don't know how to run `cobol` code snippets."],
+        );
+        assert_eq!(sessions.get(KEY).unwrap(), None);
     }
 }
@@ -0,0 +1,322 @@
+use std::{collections::HashMap, env};
+
+use futures::{stream, Stream};
+use hyper::{
+    body, body::HttpBody, client::HttpConnector, Body, Client, Method, Request, Response, Uri,
+};
+use hyperlocal::{UnixClientExt, UnixConnector};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// A bare client for the parts of the Docker Engine API that shiplift doesn't cover.
+///
+/// shiplift's `ContainerOptions` only exposes a subset of the container create parameters (e.g.
+/// there's no way to set `StdinOnce`), so we serialize its options, patch in the missing fields,
+/// and post the request ourselves.
+pub struct DockerApi {
+    transport: Transport,
+}
+
+enum Transport {
+    Unix {
+        client: Client<UnixConnector>,
+        socket: String,
+    },
+    /// Plain HTTP, like shiplift without its `tls` feature
+    Tcp {
+        client: Client<HttpConnector>,
+        /// Like `http://localhost:2375`
+        base: String,
+    },
+}
+
+/// `DOCKER_HOST` is set to something we can't connect to
+#[derive(Debug, Error)]
+#[error("DOCKER_HOST={0:?} isn't a unix:// or tcp:// address")]
+pub struct UnsupportedHost(String);
+
+impl DockerApi {
+    /// Connects to the same daemon `shiplift::Docker::new` would
+    pub fn new() -> Result<Self, UnsupportedHost> {
+        match env::var("DOCKER_HOST") {
+            Ok(host) => Self::with_host(&host),
+            Err(_) => Self::with_host("unix:///var/run/docker.sock"),
+        }
+    }
+
+    fn with_host(host: &str) -> Result<Self, UnsupportedHost> {
+        let transport = if let Some(socket) = host.strip_prefix("unix://") {
+            Transport::Unix {
+                client: Client::unix(),
+                socket: socket.to_owned(),
+            }
+        } else {
+            let addr = host
+                .strip_prefix("tcp://")
+                .or_else(|| host.strip_prefix("http://"))
+                .map(|addr| addr.trim_end_matches('/'))
+                .filter(|addr| addr.parse::<hyper::http::uri::Authority>().is_ok())
+                .ok_or_else(|| UnsupportedHost(host.to_owned()))?;
+            Transport::Tcp {
+                client: Client::new(),
+                base: format!("http://{}", addr),
+            }
+        };
+        Ok(Self { transport })
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Body,
+    ) -> shiplift::Result<Response<Body>> {
+        let request = Request::builder().method(method);
+        let response = match &self.transport {
+            Transport::Unix { client, socket } => {
+                let uri: Uri = hyperlocal::Uri::new(socket, path).into();
+                let request = request
+                    .uri(uri)
+                    .header("Content-Type", "application/json")
+                    .body(body)?;
+                client.request(request).await?
+            }
+            Transport::Tcp { client, base } => {
+                let uri: Uri = format!("{}{}", base, path).parse()?;
+                let request = request
+                    .uri(uri)
+                    .header("Content-Type", "application/json")
+                    .body(body)?;
+                client.request(request).await?
+            }
+        };
+        Ok(response)
+    }
+
+    /// Creates a container from `opts` with `overrides` merged on top, returning its ID
+    pub async fn create_container(
+        &self,
+        opts: &shiplift::ContainerOptions,
+        overrides: Value,
+    ) -> shiplift::Result<String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Response {
+            id: String,
+        }
+
+        let mut body: Value = serde_json::from_str(&opts.serialize()?)?;
+        merge(&mut body, overrides);
+
+        let body = Body::from(serde_json::to_vec(&body)?);
+        let response = self
+            .request(Method::POST, "/containers/create", body)
+            .await?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(shiplift::Error::Fault {
+                code: status,
+                message: String::from_utf8_lossy(&bytes).into_owned(),
+            });
+        }
+        let response: Response = serde_json::from_slice(&bytes)?;
+        Ok(response.id)
+    }
+
+    /// The security features the daemon supports, like `name=seccomp,profile=default`
+    pub async fn security_options(&self) -> shiplift::Result<Vec<String>> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Info {
+            #[serde(default)]
+            security_options: Vec<String>,
+        }
+
+        let info: Info = self.get_json("/info").await?;
+        Ok(info.security_options)
+    }
+
+    /// The security settings the daemon actually gave a container
+    pub async fn container_security(&self, id: &str) -> shiplift::Result<ContainerSecurity> {
+        self.get_json(&format!("/containers/{}/json", id)).await
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> shiplift::Result<T> {
+        let response = self.request(Method::GET, path, Body::empty()).await?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(shiplift::Error::Fault {
+                code: status,
+                message: String::from_utf8_lossy(&bytes).into_owned(),
+            });
+        }
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Streams a running container's resource usage, about once a second. shiplift can't parse
+    /// these on cgroup v2 hosts
+    pub async fn stats(
+        &self,
+        id: &str,
+    ) -> shiplift::Result<impl Stream<Item = shiplift::Result<Stats>>> {
+        let path = format!("/containers/{}/stats", id);
+        let response = self.request(Method::GET, &path, Body::empty()).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let bytes = body::to_bytes(response.into_body()).await?;
+            return Err(shiplift::Error::Fault {
+                code: status,
+                message: String::from_utf8_lossy(&bytes).into_owned(),
+            });
+        }
+        // One JSON object per line
+        let lines = (response.into_body(), Vec::new());
+        Ok(stream::unfold(lines, |(mut body, mut buf)| async move {
+            loop {
+                if let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buf.drain(..=end).collect();
+                    let stats = serde_json::from_slice(&line).map_err(Into::into);
+                    return Some((stats, (body, buf)));
+                }
+                match body.data().await? {
+                    Ok(bytes) => buf.extend_from_slice(&bytes),
+                    Err(err) => return Some((Err(err.into()), (body, buf))),
+                }
+            }
+        }))
+    }
+}
+
+/// The parts of a container's details that say how it's confined
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerSecurity {
+    #[serde(default)]
+    pub app_armor_profile: String,
+    /// The SELinux label its processes run with
+    #[serde(default)]
+    pub process_label: String,
+    pub host_config: SecurityConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SecurityConfig {
+    /// Null rather than empty when there aren't any
+    pub security_opt: Option<Vec<String>>,
+}
+
+/// The parts of a container's stats we care about
+#[derive(Debug, Default, Deserialize)]
+pub struct Stats {
+    #[serde(default)]
+    pub cpu_stats: CpuStats,
+    #[serde(default)]
+    pub memory_stats: MemoryStats,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CpuStats {
+    #[serde(default)]
+    pub cpu_usage: CpuUsage,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CpuUsage {
+    /// In nanoseconds
+    #[serde(default)]
+    pub total_usage: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MemoryStats {
+    /// Includes the page cache
+    #[serde(default)]
+    pub usage: u64,
+    /// Named differently on cgroup v1 and v2
+    #[serde(default)]
+    pub stats: HashMap<String, u64>,
+}
+
+impl MemoryStats {
+    /// Memory use without the page cache it could give back, the same as `docker stats` shows
+    pub fn working_set(&self) -> u64 {
+        let cache = self
+            .stats
+            .get("inactive_file")
+            .or_else(|| self.stats.get("total_inactive_file"))
+            .copied()
+            .unwrap_or_default();
+        self.usage.saturating_sub(cache)
+    }
+}
+
+/// Recursively merges `patch` into `base`, with `patch` winning on conflicts
+fn merge(base: &mut Value, patch: Value) {
+    match (base, patch) {
+        (Value::Object(base), Value::Object(patch)) => {
+            for (k, v) in patch {
+                merge(base.entry(k).or_insert(Value::Null), v);
+            }
+        }
+        (base, patch) => *base = patch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats: Stats = serde_json::from_value(json!({
+            "read": "0001-01-01T00:00:00Z",
+            "cpu_stats": {"cpu_usage": {"total_usage": 1500}},
+            "memory_stats": {"usage": 1000, "stats": {"inactive_file": 300, "anon": 700}},
+        }))
+        .unwrap();
+        assert_eq!(stats.cpu_stats.cpu_usage.total_usage, 1500);
+        assert_eq!(stats.memory_stats.working_set(), 700);
+
+        // A stopped container has empty stats
+        let stats: Stats =
+            serde_json::from_value(json!({"cpu_stats": {}, "memory_stats": {}})).unwrap();
+        assert_eq!(stats.memory_stats.working_set(), 0);
+    }
+
+    #[test]
+    fn test_hosts() {
+        let base = |host| match DockerApi::with_host(host).unwrap().transport {
+            Transport::Tcp { base, .. } => base,
+            Transport::Unix { socket, .. } => socket,
+        };
+        assert_eq!(base("unix:///run/docker.sock"), "/run/docker.sock");
+        assert_eq!(base("tcp://10.0.0.2:2375"), "http://10.0.0.2:2375");
+        assert_eq!(base("http://docker:2375/"), "http://docker:2375");
+        for host in ["ssh://user@docker", "tcp://", "docker:2375"] {
+            assert!(DockerApi::with_host(host).is_err(), "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base = json!({"User": "nobody", "HostConfig": {"Memory": 1}});
+        merge(
+            &mut base,
+            json!({"StdinOnce": true, "HostConfig": {"NanoCpus": 2}}),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "User": "nobody",
+                "StdinOnce": true,
+                "HostConfig": {"Memory": 1, "NanoCpus": 2},
+            }),
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
 use thiserror::Error;
 use unicase::Ascii;
@@ -17,9 +17,17 @@ pub trait Language: fmt::Display {
     // From https://github.com/highlightjs/highlight.js/blob/master/SUPPORTED_LANGUAGES.md.
     fn codes(&self) -> &[Ascii<&str>];
     fn run_spec(&self, opts: Options) -> anyhow::Result<RunSpec, OptionsError>;
+    /// An interpreter that keeps running snippets for `#!session`, for languages that have one.
+    /// It's run in `run_spec`'s image and has to speak the protocol described on
+    /// `runner::session_request`
+    fn session_cmd(&self) -> Option<Vec<String>> {
+        None
+    }
 }
 
 pub type LangRef = &'static (dyn Language + Send + Sync);
+/// Every language, by each of its codes
+pub type Langs = HashMap<Ascii<&'static str>, LangRef>;
 inventory::collect!(LangRef);
 
 macro_rules! make_lang {
@@ -45,11 +53,14 @@ macro_rules! test_lang {
             async fn [<test_ $lang:lower>]() {
                 let output = $crate::runner::test_run(&$lang, $code).await.unwrap();
                 assert_eq!(
-                    output,
-                    $crate::runner::Output {
+                    output.run,
+                    Some($crate::runner::Output {
                         status: 0,
-                        tty: "Hello, World!\n".into(),
-                    }
+                        termination: $crate::runner::Termination::Exited(0),
+                        tty: vec![$crate::runner::Chunk::Stdout("Hello, World!\n".into())],
+                        usage: None,
+                        files: Default::default(),
+                    })
                 );
             }
         }
@@ -72,6 +83,10 @@ macro_rules! CODES {
     )
 }
 
+macro_rules! cmd {
+    ($($args:expr),*$(,)?) => (vec![$(String::from($args)),*])
+}
+
 macro_rules! bind_opts {
     ( $map:expr => {$( $vars:ident or $default:literal ),*$(,)?} ) => (
         #[allow(unused_parens, unused_mut)]
@@ -94,9 +109,10 @@ impl Language for Sh {
         Ok(RunSpec {
             image_name: "sh".to_owned(),
             code_path: "run.sh",
+            compile: None,
+            run: cmd!["sh", "run.sh"],
             dockerfile: r#"
 FROM alpine:3.15
-CMD ["sh", "run.sh"]
 "#
             .to_owned(),
         })
@@ -112,10 +128,11 @@ impl Language for Bash {
         Ok(RunSpec {
             image_name: "bash".to_owned(),
             code_path: "run.sh",
+            compile: None,
+            run: cmd!["bash", "run.sh"],
             dockerfile: r#"
 FROM alpine:3.15
 RUN apk add --no-cache bash
-CMD ["bash", "run.sh"]
 "#
             .to_owned(),
         })
@@ -131,10 +148,11 @@ impl Language for Zsh {
         Ok(RunSpec {
             image_name: "zsh".to_owned(),
             code_path: "run.sh",
+            compile: None,
+            run: cmd!["zsh", "run.sh"],
             dockerfile: r#"
 FROM alpine:3.15
 RUN apk add --no-cache zsh
-CMD ["zsh", "run.sh"]
 "#
             .to_owned(),
         })
@@ -150,9 +168,10 @@ impl Language for PowerShell {
         Ok(RunSpec {
             image_name: "powershell".to_owned(),
             code_path: "run.ps1",
+            compile: None,
+            run: cmd!["pwsh", "run.ps1"],
             dockerfile: r#"
 FROM mcr.microsoft.com/powershell:debian-buster-slim
-CMD ["pwsh", "run.ps1"]
 "#
             .to_owned(),
         })
@@ -171,25 +190,70 @@ impl Language for Python {
         };
         let pip_install = match bundle.as_str() {
             "none" => "",
-            "scipy" => "RUN pip install numpy scipy sympy",
+            // Plots can only be saved to files, and matplotlib's cache has to go somewhere writable
+            "scipy" => {
+                "RUN pip install numpy scipy sympy matplotlib\nENV MPLBACKEND=Agg MPLCONFIGDIR=/tmp/.matplotlib"
+            }
             _ => return Err(OptionsError::UnknownValue(bundle)),
         };
         Ok(RunSpec {
             image_name: format!("python-{}-{}", version, bundle),
             code_path: "run.py",
+            compile: None,
+            run: cmd!["python", "run.py"],
             dockerfile: format!(
                 r#"
 FROM python:{version}-slim-buster
 ENV PYTHONUNBUFFERED=1
 {pip_install}
-CMD ["python", "run.py"]
 "#,
             ),
         })
     }
+
+    fn session_cmd(&self) -> Option<Vec<String>> {
+        Some(cmd!["python", "-c", PYTHON_SESSION])
+    }
 }
 test_lang!(Python, "print('Hello, World!')");
 
+/// Runs every snippet in the same globals, like a notebook. Tracebacks leave out the session's own
+/// frame
+const PYTHON_SESSION: &str = r#"
+import io, sys, traceback
+marker = sys.argv[1]
+requests = sys.stdin.buffer
+scope = {"__name__": "__main__"}
+while True:
+    header = requests.readline()
+    if not header:
+        break
+    code_len, stdin_len = map(int, header.split())
+    code = requests.read(code_len).decode()
+    sys.stdin = io.TextIOWrapper(io.BytesIO(requests.read(stdin_len)))
+    status = 0
+    try:
+        exec(compile(code, "run.py", "exec"), scope)
+    except SystemExit as exit:
+        if isinstance(exit.code, int):
+            status = exit.code
+        elif exit.code is not None:
+            print(exit.code, file=sys.stderr)
+            status = 1
+    except BaseException:
+        etype, value, tb = sys.exc_info()
+        traceback.print_exception(etype, value, tb.tb_next)
+        status = 1
+    for stream in (sys.stdout, sys.stderr, sys.__stdout__, sys.__stderr__):
+        try:
+            stream.flush()
+        except Exception:
+            pass
+    for stream in (sys.__stdout__, sys.__stderr__):
+        stream.write("%s %d\n" % (marker, status & 0xFF))
+        stream.flush()
+"#;
+
 make_lang!(JavaScript);
 impl Language for JavaScript {
     CODES!["javascript", "js", "jsx"];
@@ -202,10 +266,11 @@ impl Language for JavaScript {
         Ok(RunSpec {
             image_name: format!("nodejs-{}", version),
             code_path: "index.js",
+            compile: None,
+            run: cmd!["node", "index.js"],
             dockerfile: format!(
                 r#"
 FROM node:{version}-alpine
-CMD ["node", "index.js"]
 "#,
             ),
         })
@@ -221,6 +286,8 @@ impl Language for TypeScript {
         Ok(RunSpec {
             image_name: "typescript".to_owned(),
             code_path: "index.ts",
+            compile: None,
+            run: cmd!["/bin/deno", "run", "--quiet", "index.ts"],
             // This is taken from https://github.com/hayd/deno-docker/blob/master/distroless.dockerfile
             dockerfile: r#"
 FROM alpine:3.12.3
@@ -243,7 +310,6 @@ COPY --from=0 /bin/deno /bin/deno
 ENV DENO_VERSION=1.7.2
 ENV DENO_DIR deno
 ENV DENO_INSTALL_ROOT /usr/local
-CMD ["/bin/deno", "run", "--quiet", "index.ts"]
 "#
             .to_owned(),
         })
@@ -259,9 +325,10 @@ impl Language for Perl {
         Ok(RunSpec {
             image_name: "perl".to_owned(),
             code_path: "run.pl",
+            compile: None,
+            run: cmd!["perl", "run.pl"],
             dockerfile: r#"
 FROM perl:slim-buster
-CMD ["perl", "run.pl"]
 "#
             .to_owned(),
         })
@@ -277,9 +344,10 @@ impl Language for Php {
         Ok(RunSpec {
             image_name: "php".to_owned(),
             code_path: "run.php",
+            compile: None,
+            run: cmd!["php", "run.php"],
             dockerfile: r#"
 FROM php:8.0-alpine
-CMD ["php", "run.php"]
 "#
             .to_owned(),
         })
@@ -300,10 +368,11 @@ impl Language for Ruby {
         Ok(RunSpec {
             image_name: format!("ruby-{}", version),
             code_path: "run.rb",
+            compile: None,
+            run: cmd!["ruby", "run.rb"],
             dockerfile: format!(
                 r#"
 FROM ruby:{version}-alpine
-CMD ["ruby", "run.rb"]
 "#,
             ),
         })
@@ -324,11 +393,12 @@ impl Language for Lua {
         Ok(RunSpec {
             image_name: format!("lua-{}", version),
             code_path: "run.lua",
+            compile: None,
+            run: cmd![format!("lua{}", version), "run.lua"],
             dockerfile: format!(
                 r#"
 FROM alpine:edge
 RUN apk add --no-cache lua{version}
-CMD ["lua{version}", "run.lua"]
 "#,
             ),
         })
@@ -348,10 +418,11 @@ impl Language for Julia {
         Ok(RunSpec {
             image_name: format!("julia-{}", version),
             code_path: "run.jl",
+            compile: None,
+            run: cmd!["julia", "run.jl"],
             dockerfile: format!(
                 r#"
 FROM julia:{version}
-CMD ["julia", "run.jl"]
 "#,
             ),
         })
@@ -367,9 +438,10 @@ impl Language for R {
         Ok(RunSpec {
             image_name: "r".to_owned(),
             code_path: "run.R",
+            compile: None,
+            run: cmd!["Rscript", "run.R"],
             dockerfile: r#"
 FROM r-base
-CMD ["Rscript", "run.R"]
 "#
             .to_owned(),
         })
@@ -389,12 +461,14 @@ impl Language for Go {
         Ok(RunSpec {
             image_name: format!("golang-{}", version),
             code_path: "main.go",
+            compile: None,
+            // Build every file in the package
+            run: cmd!["sh", "-c", r#"go run *.go "$@""#, "sh"],
             dockerfile: format!(
                 r#"
 FROM golang:{version}-alpine
 # So that we can build code
 ENV GOCACHE=/tmp/.cache/go
-CMD ["go", "run", "main.go"]
 "#,
             ),
         })
@@ -410,6 +484,9 @@ func main() {
 }"#
 );
 
+// The sed command grabs the classname from `public class Ident`
+const JAVA_CLASS: &str = r#"$(sed -n "s/public\s\+class\s\+\(\w\+\).*/\1/p" code)"#;
+
 make_lang!(Java);
 impl Language for Java {
     CODES!["java", "jsp"];
@@ -422,13 +499,15 @@ impl Language for Java {
         Ok(RunSpec {
             image_name: format!("java-openjdk-{}", version),
             code_path: "code",
+            compile: Some(cmd![
+                "sh",
+                "-c",
+                format!("ln -s code {JAVA_CLASS}.java && javac {JAVA_CLASS}.java"),
+            ]),
+            run: cmd!["sh", "-c", format!(r#"java {JAVA_CLASS} "$@""#), "sh"],
             dockerfile: format!(
                 r#"
 FROM openjdk:{version}-jdk-slim-buster
-# The sed command grabs the classname from `public class Ident`
-CMD sh -c \
-    'class=$(sed -n "s/public\s\+class\s\+\(\w\+\).*/\1/p" code); \
-     ln -s code $class.java && javac $class.java && java $class'
 "#,
             ),
         })
@@ -452,6 +531,12 @@ impl Language for Kotlin {
         Ok(RunSpec {
             image_name: "kotlin".to_owned(),
             code_path: "main.kt",
+            compile: Some(cmd![
+                "sh",
+                "-c",
+                "kotlinc *.kt -include-runtime -d main.jar",
+            ]),
+            run: cmd!["java", "-jar", "main.jar"],
             dockerfile: r#"
 FROM openjdk:11-jre-slim
 RUN apt-get update && apt-get install -y --no-install-recommends wget unzip && \
@@ -465,7 +550,6 @@ RUN apt-get update && apt-get install -y --no-install-recommends wget unzip && \
     rm kotlin-compiler-*.zip && \
     rm -f kotlinc/bin/*.bat
 ENV PATH $PATH:/usr/lib/kotlinc/bin
-CMD ["sh", "-c", "kotlinc main.kt -include-runtime -d main.jar && java -jar main.jar"]
 "#
             .to_owned(),
         })
@@ -492,10 +576,11 @@ impl Language for Groovy {
         Ok(RunSpec {
             image_name: format!("groovy{}", version),
             code_path: "run.groovy",
+            compile: None,
+            run: cmd!["groovy", "run.groovy"],
             dockerfile: format!(
                 r#"
 FROM groovy:{version}-jre11
-CMD ["groovy", "run.groovy"]
 "#,
             ),
         })
@@ -511,9 +596,10 @@ impl Language for CSharp {
         Ok(RunSpec {
             image_name: "csharp".to_owned(),
             code_path: "main.cs",
+            compile: Some(cmd!["sh", "-c", "mcs -out:main.exe *.cs"]),
+            run: cmd!["mono", "main.exe"],
             dockerfile: r#"
 FROM mono:6.12
-CMD ["sh", "-c", "mcs -out:main.exe main.cs && mono main.exe" ]
 "#
             .to_owned(),
         })
@@ -541,11 +627,13 @@ impl Language for Swift {
         Ok(RunSpec {
             image_name: format!("swift-{}", version),
             code_path: "main.swift",
+            // Only main.swift can have top-level code, so the rest can go in any order
+            compile: Some(cmd!["sh", "-c", "swiftc *.swift -o main"]),
+            run: cmd!["./main"],
             dockerfile: format!(
                 r#"
 FROM swift:{version}
 ENV XDG_CACHE_HOME=/tmp/.cache
-CMD ["swift", "main.swift" ]
 "#,
             ),
         })
@@ -561,9 +649,10 @@ impl Language for Dart {
         Ok(RunSpec {
             image_name: "dart".to_owned(),
             code_path: "main.dart",
+            compile: None,
+            run: cmd!["dart", "main.dart"],
             dockerfile: r#"
 FROM google/dart
-CMD ["dart", "main.dart"]
 "#
             .to_owned(),
         })
@@ -585,9 +674,10 @@ impl Language for CommonLisp {
         Ok(RunSpec {
             image_name: "common-lisp".to_owned(),
             code_path: "run.lsp",
+            compile: None,
+            run: cmd!["sbcl", "--script", "run.lsp"],
             dockerfile: r#"
 FROM clfoundation/sbcl:2.1.2-alpine3.13
-CMD ["sbcl", "--script", "run.lsp"]
 "#
             .to_owned(),
         })
@@ -603,9 +693,10 @@ impl Language for Racket {
         Ok(RunSpec {
             image_name: "racket".to_owned(),
             code_path: "run.rkt",
+            compile: None,
+            run: cmd!["racket", "--load", "run.rkt"],
             dockerfile: r#"
 FROM racket/racket:8.0
-CMD ["racket", "--load", "run.rkt"]
 "#
             .to_owned(),
         })
@@ -621,9 +712,10 @@ impl Language for Haskell {
         Ok(RunSpec {
             image_name: "haskell".to_owned(),
             code_path: "main.hs",
+            compile: None,
+            run: cmd!["runhaskell", "main.hs"],
             dockerfile: r#"
 FROM haskell
-CMD ["runhaskell", "main.hs"]
 "#
             .to_owned(),
         })
@@ -649,10 +741,11 @@ impl Language for Erlang {
         Ok(RunSpec {
             image_name: format!("erlang-{}", version),
             code_path: "main.erl",
+            compile: None,
+            run: cmd!["escript", "main.erl"],
             dockerfile: format!(
                 r#"
 FROM erlang:{version}-alpine
-CMD ["escript", "main.erl"]
 "#,
             ),
         })
@@ -678,10 +771,11 @@ impl Language for Elixir {
         Ok(RunSpec {
             image_name: format!("elixir-{}", version),
             code_path: "run.exs",
+            compile: None,
+            run: cmd!["elixir", "run.exs"],
             dockerfile: format!(
                 r#"
 FROM elixir:{version}-alpine
-CMD ["elixir", "run.exs"]
 "#,
             ),
         })
@@ -697,10 +791,11 @@ impl Language for OCaml {
         Ok(RunSpec {
             image_name: "ocaml".to_owned(),
             code_path: "main.ml",
+            compile: None,
+            run: cmd!["ocaml", "main.ml"],
             dockerfile: r#"
 FROM alpine:3.13
 RUN apk add --no-cache ocaml
-CMD ["ocaml", "main.ml"]
 "#
             .to_owned(),
         })
@@ -717,9 +812,10 @@ impl Language for C {
         Ok(RunSpec {
             image_name: "c-gcc".to_owned(),
             code_path: "main.c",
+            compile: Some(cmd!["sh", "-c", "gcc -Wall -Wextra *.c -o main"]),
+            run: cmd!["./main"],
             dockerfile: r#"
 FROM gcc:latest
-CMD ["sh", "-c", "gcc -Wall -Wextra main.c -o main && ./main"]
 "#
             .to_owned(),
         })
@@ -744,9 +840,10 @@ impl Language for Cpp {
         Ok(RunSpec {
             image_name: "cpp-gcc".to_owned(),
             code_path: "main.cpp",
+            compile: Some(cmd!["sh", "-c", "g++ -Wall -Wextra *.cpp -o main"]),
+            run: cmd!["./main"],
             dockerfile: r#"
 FROM gcc:latest
-CMD ["sh", "-c", "g++ -Wall -Wextra main.cpp -o main && ./main"]
 "#
             .to_owned(),
         })
@@ -771,9 +868,10 @@ impl Language for Rust {
         Ok(RunSpec {
             image_name: "rust".to_owned(),
             code_path: "main.rs",
+            compile: Some(cmd!["rustc", "main.rs", "-o", "main"]),
+            run: cmd!["./main"],
             dockerfile: r#"
 FROM rust:alpine
-CMD ["sh", "-c", "rustc main.rs -o main && ./main"]
 "#
             .to_owned(),
         })
@@ -795,9 +893,15 @@ impl Language for Fortran {
         Ok(RunSpec {
             image_name: "fortran".to_owned(),
             code_path: "main.f95",
+            // Modules have to be compiled before the files that use them, so main.f95 goes last
+            compile: Some(cmd![
+                "sh",
+                "-c",
+                "gfortran -Wall -Wextra $(ls *.f95 | grep -vx main.f95) main.f95 -o main",
+            ]),
+            run: cmd!["./main"],
             dockerfile: r#"
 FROM gcc:latest
-CMD ["sh", "-c", "gfortran -Wall -Wextra main.f95 -o main && ./main"]
 "#
             .to_owned(),
         })
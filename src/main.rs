@@ -1,34 +1,136 @@
+mod backend;
+mod bwrap_runner;
+mod cli_runner;
 mod discord;
+mod docker_api;
 mod lang;
 mod options_parser;
+mod pool;
+mod rate_limit;
 mod runner;
+mod scheduler;
+mod session;
 
-use std::{collections::HashMap, env, time::Duration};
+use std::{collections::HashMap, env, path::PathBuf, sync::Arc, time::Duration};
 
 use serde::Deserialize;
 use serenity::client::Client;
 use shiplift::Docker;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing_log::LogTracer;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use unicase::Ascii;
 
 use crate::{
+    backend::Backend,
+    bwrap_runner::BwrapRunner,
+    cli_runner::CliRunner,
     discord::{Handler, MessageIds},
+    docker_api::DockerApi,
     lang::LangRef,
-    runner::DockerRunner,
+    pool::WarmPool,
+    rate_limit::{RateLimitConfig, RateLimits},
+    runner::{
+        DockerRunner, Hardening, ProcessLimits, RunIds, DEFAULT_HOLDER_IMAGE, DEFAULT_INSTANCE,
+    },
+    scheduler::Scheduler,
+    session::{SessionConfig, Sessions},
 };
 
 #[derive(Deserialize)]
 struct Config {
     log_filter: String,
     docker: DockerConfig,
+    #[serde(default)]
+    queue: QueueConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    session: SessionConfig,
     discord_token: String,
 }
 
 #[derive(Deserialize)]
 struct DockerConfig {
+    #[serde(default)]
+    backend: BackendKind,
+    /// Tells this bot's containers apart from those of others sharing the same daemon or cgroup,
+    /// so that cleaning up after a crash doesn't kill their runs. Letters, digits and underscores
+    /// only. Defaults to `codie`
+    instance: Option<String>,
     timeout_secs: u64,
+    /// Defaults to `timeout_secs`
+    compile_timeout_secs: Option<u64>,
     memory_bytes: u64,
     cpus: f64,
+    /// Defaults to 1 MiB
+    max_output_bytes: Option<usize>,
+    /// How big each run's working directory can get. Defaults to 256 MiB. Only the docker backend
+    /// limits it
+    workdir_bytes: Option<u64>,
+    /// Whether programs can only write to their working directory. Defaults to true. Only the
+    /// docker backend supports turning it off, and only the docker and bubblewrap backends enforce
+    /// it
+    read_only_rootfs: Option<bool>,
+    /// How many files a run can send back from its output directory. Defaults to 8. Only the
+    /// docker backend sends any
+    max_files: Option<usize>,
+    /// How big those files can be in total. Defaults to 4 MiB
+    max_files_bytes: Option<u64>,
+    #[serde(default)]
+    limits: ProcessLimits,
+    #[serde(default)]
+    hardening: Hardening,
+    /// How many containers to keep ready for each language code, like `java = 2`. Only the docker
+    /// backend has a pool
+    #[serde(default)]
+    pool: HashMap<String, usize>,
+    /// The image that holds each run's working directory. Defaults to `registry.k8s.io/pause:3.9`.
+    /// Set it to one that's already on the host to avoid pulling from a registry. Only the docker
+    /// backend uses it
+    holder_image: Option<String>,
+    /// Required by the bubblewrap backend
+    bubblewrap: Option<BubblewrapConfig>,
+}
+
+/// What runs the containers
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+enum BackendKind {
+    /// The Docker Engine API over its unix socket
+    #[default]
+    Docker,
+    /// The `podman` command line
+    Podman,
+    /// The `docker` command line
+    DockerCli,
+    /// Linux namespaces through `bwrap`, without any containers
+    Bubblewrap,
+}
+
+#[derive(Deserialize)]
+struct BubblewrapConfig {
+    /// Holds a root filesystem directory for each language's image name
+    rootfs_dir: PathBuf,
+    /// A delegated cgroup v2 directory to enforce `cpus` and `memory_bytes` with
+    cgroup_root: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct QueueConfig {
+    /// How many runs can happen at once
+    max_running: usize,
+    /// How many runs can wait for their turn before we start turning people away
+    max_queued: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_running: 4,
+            max_queued: 32,
+        }
+    }
 }
 
 #[tokio::main]
@@ -72,28 +174,176 @@ async fn main() {
 
     let db = sled::open("data").expect("failed to open sled database");
 
+    let timeout = Duration::from_secs(conf.docker.timeout_secs);
+    let compile_timeout = Duration::from_secs(
+        conf.docker
+            .compile_timeout_secs
+            .unwrap_or(conf.docker.timeout_secs),
+    );
+    let max_output_bytes = conf.docker.max_output_bytes.unwrap_or(1024 * 1024);
+    let instance = conf
+        .docker
+        .instance
+        .clone()
+        .unwrap_or_else(|| DEFAULT_INSTANCE.to_owned());
+    let run_ids = || RunIds::new(instance.clone()).expect("invalid docker.instance");
+    let cli_runner = |program: &str| {
+        tracing::warn!(
+            "The {} backend doesn't limit the working directory's size, make the root \
+             filesystem read-only or send back files",
+            program
+        );
+        CliRunner {
+            program: program.to_owned(),
+            timeout,
+            compile_timeout,
+            cpus: conf.docker.cpus,
+            memory_bytes: conf.docker.memory_bytes,
+            max_output_bytes,
+            limits: conf.docker.limits,
+            hardening: conf.docker.hardening.clone(),
+            builds: Default::default(),
+            run_ids: run_ids(),
+        }
+    };
+    let pool_targets = conf
+        .docker
+        .pool
+        .iter()
+        .map(|(code, &size)| {
+            let lang = langs
+                .get(&Ascii::new(code.as_str()))
+                .unwrap_or_else(|| panic!("there's no language {:?} to pool", code));
+            let spec = lang
+                .run_spec(Default::default())
+                .expect("failed to get a language's default run spec");
+            (spec, size)
+        })
+        .collect();
+    let backend: Arc<dyn Backend> = match conf.docker.backend {
+        BackendKind::Docker => Arc::new(DockerRunner {
+            docker: Docker::new(),
+            api: DockerApi::new().expect("can't connect to the docker daemon"),
+            timeout,
+            compile_timeout,
+            cpus: conf.docker.cpus,
+            memory_bytes: conf.docker.memory_bytes,
+            max_output_bytes,
+            workdir_bytes: conf.docker.workdir_bytes.unwrap_or(256 * 1024 * 1024),
+            read_only_rootfs: conf.docker.read_only_rootfs.unwrap_or(true),
+            max_files: conf.docker.max_files.unwrap_or(8),
+            max_files_bytes: conf.docker.max_files_bytes.unwrap_or(4 * 1024 * 1024),
+            limits: conf.docker.limits,
+            hardening: conf.docker.hardening,
+            pool: WarmPool::new(pool_targets),
+            sessions: Default::default(),
+            builds: Default::default(),
+            run_ids: run_ids(),
+            holder_image: conf
+                .docker
+                .holder_image
+                .unwrap_or_else(|| DEFAULT_HOLDER_IMAGE.to_owned()),
+        }),
+        BackendKind::Podman => Arc::new(cli_runner("podman")),
+        BackendKind::DockerCli => Arc::new(cli_runner("docker")),
+        BackendKind::Bubblewrap => {
+            let bwrap = conf
+                .docker
+                .bubblewrap
+                .expect("the bubblewrap backend needs a [docker.bubblewrap] section");
+            if bwrap.cgroup_root.is_none() {
+                tracing::warn!("No cgroup_root, so runs won't have CPU or memory limits");
+            }
+            Arc::new(BwrapRunner {
+                rootfs_dir: bwrap.rootfs_dir,
+                cgroup_root: bwrap.cgroup_root,
+                timeout,
+                compile_timeout,
+                cpus: conf.docker.cpus,
+                memory_bytes: conf.docker.memory_bytes,
+                max_output_bytes,
+                limits: conf.docker.limits,
+                hardening: conf.docker.hardening,
+                run_ids: run_ids(),
+            })
+        }
+    };
+
+    if let Err(err) = backend.prepare().await {
+        panic!("failed to prepare the backend: {}", err);
+    }
+
+    // Running code with less confinement than was asked for would be worse than not running it
+    if let Err(err) = backend.check_hardening().await {
+        panic!("refusing to run: {}", err);
+    }
+
+    // Whatever a crashed process was running is of no use to anyone
+    match backend.remove_containers().await {
+        Ok(0) => {}
+        Ok(n) => tracing::info!("Removed {} containers left over from before", n),
+        Err(err) => tracing::error!("Failed to remove leftover containers: {}", err),
+    }
+    let sessions = Sessions::new(
+        db.open_tree("sessions")
+            .expect("failed to open sessions db"),
+        conf.session,
+    );
+    // Their containers went with the rest
+    sessions.clear().expect("failed to clear sessions");
+    tokio::spawn({
+        let backend = backend.clone();
+        async move { backend.fill_pool().await }
+    });
+    tokio::spawn(sessions.clone().expire_idle(backend.clone()));
+
     // Login with a bot token from the environment
     let mut client = Client::builder(&conf.discord_token)
         .event_handler(Handler {
             language_text: language_text.join("\n").into_boxed_str(),
-            runner: DockerRunner {
-                docker: Docker::new(),
-                langs,
-                timeout: Duration::from_secs(conf.docker.timeout_secs),
-                cpus: conf.docker.cpus,
-                memory_bytes: conf.docker.memory_bytes,
-            },
+            langs,
+            backend: backend.clone(),
+            scheduler: Scheduler::new(conf.queue.max_running, conf.queue.max_queued),
             message_ids: MessageIds::new(
                 db.open_tree("message_ids")
                     .expect("failed to open message_ids db"),
             ),
+            rate_limits: RateLimits::new(
+                db.open_tree("rate_limits")
+                    .expect("failed to open rate_limits db"),
+                conf.rate_limit,
+            ),
+            sessions,
         })
         .await
         .expect("failed to build client");
 
-    // Start as many shards as Discord recommends
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        tracing::info!("Shutting down");
+        // Stop taking new work and kill whatever's still running. The client stops, and we exit,
+        // once the shards are down
+        backend.close();
+        match backend.remove_containers().await {
+            Ok(n) => tracing::info!("Removed {} containers", n),
+            Err(err) => tracing::error!("Failed to remove containers: {}", err),
+        }
+        shard_manager.lock().await.shutdown_all().await;
+    });
+
+    // Start as many shards as Discord recommends. Returns once we've shut down
     client
         .start_autosharded()
         .await
         .expect("failed to start discord client");
 }
+
+/// Waits for SIGTERM or SIGINT
+async fn wait_for_shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        result = tokio::signal::ctrl_c() => result.expect("failed to listen for SIGINT"),
+    }
+}
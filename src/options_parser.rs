@@ -7,11 +7,13 @@ use nom::{
     character::complete::{alpha1, alphanumeric1, char, multispace0, multispace1},
     combinator::{cut, map, recognize, value, verify},
     error::context,
-    multi::{many0, separated_list0},
+    multi::{many0, separated_list0, separated_list1},
     sequence::{pair, preceded, separated_pair, terminated},
     IResult,
 };
 
+use crate::runner::OutputMode;
+
 pub type Options<'s> = HashMap<&'s str, String>;
 
 fn quoteless_string(i: &str) -> IResult<&str, &str> {
@@ -49,8 +51,13 @@ fn identifier(i: &str) -> IResult<&str, &str> {
     )(i)
 }
 
+// Keys may be namespaced like `env.NAME`
+fn key(i: &str) -> IResult<&str, &str> {
+    context("key", recognize(separated_list1(char('.'), identifier)))(i)
+}
+
 fn key_value(i: &str) -> IResult<&str, (&str, String)> {
-    separated_pair(identifier, char('='), string)(i)
+    separated_pair(key, char('='), string)(i)
 }
 
 fn config(i: &str) -> IResult<&str, Vec<(&str, String)>> {
@@ -58,7 +65,7 @@ fn config(i: &str) -> IResult<&str, Vec<(&str, String)>> {
 }
 
 // TODO: Make this generic with what it returns
-pub fn parse_options(conf: &str) -> anyhow::Result<Options> {
+pub fn parse_options(conf: &str) -> anyhow::Result<Options<'_>> {
     match config(conf) {
         Ok(("", vec)) => {
             let mut map = HashMap::new();
@@ -74,6 +81,55 @@ pub fn parse_options(conf: &str) -> anyhow::Result<Options> {
     }
 }
 
+fn arg_list(i: &str) -> IResult<&str, Vec<String>> {
+    preceded(
+        multispace0,
+        terminated(separated_list0(multispace1, string), multispace0),
+    )(i)
+}
+
+/// Options shared by every language, which are applied to the container rather than being
+/// handled by `Language::run_spec`
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct RunOptions {
+    /// Passed to the program after its own arguments
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    /// How to show the program's stdout and stderr
+    pub output: OutputMode,
+}
+
+/// Removes the options reserved for `RunOptions` from `opts`
+pub fn take_run_options(opts: &mut Options) -> anyhow::Result<RunOptions> {
+    let mut run_opts = RunOptions::default();
+    if let Some(args) = opts.remove("args") {
+        run_opts.args = match arg_list(&args) {
+            Ok(("", args)) => args,
+            Ok((input, _)) => return Err(anyhow!("did not consume entire args: {:?}", input)),
+            Err(err) => return Err(anyhow!("{}", err)),
+        };
+    }
+    if let Some(output) = opts.remove("output") {
+        run_opts.output = output.parse()?;
+    }
+    let env_keys: Vec<&str> = opts
+        .keys()
+        .copied()
+        .filter(|key| key.starts_with("env."))
+        .collect();
+    for key in env_keys {
+        let name = &key["env.".len()..];
+        if name.contains('.') {
+            return Err(anyhow!("invalid environment variable name {:?}", name));
+        }
+        let val = opts.remove(key).unwrap();
+        run_opts.env.push((name.to_owned(), val));
+    }
+    // Keep the order stable regardless of how the map iterates
+    run_opts.env.sort();
+    Ok(run_opts)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,4 +197,69 @@ mod tests {
             hashmap!["CFLAGS" => "-O2 -march=native".into()],
         );
     }
+
+    #[test]
+    fn test_dotted_key() {
+        assert_eq!(
+            parse_options("env.RUST_LOG=debug").unwrap(),
+            hashmap!["env.RUST_LOG" => "debug".into()],
+        );
+    }
+
+    #[test]
+    fn test_bad_dotted_key() {
+        if let Ok(v) = parse_options("env.=debug") {
+            panic!("{:?}", v);
+        }
+    }
+
+    #[test]
+    fn test_run_options() {
+        let mut opts =
+            parse_options(r#"version=3.8 args="-v \"two words\"" env.A=1 env.B="x y""#).unwrap();
+        assert_eq!(
+            take_run_options(&mut opts).unwrap(),
+            RunOptions {
+                args: vec!["-v".into(), "two words".into()],
+                env: vec![("A".into(), "1".into()), ("B".into(), "x y".into())],
+                output: OutputMode::Interleaved,
+            },
+        );
+        assert_eq!(opts, hashmap!["version" => "3.8".into()]);
+    }
+
+    #[test]
+    fn test_no_run_options() {
+        let mut opts = parse_options("version=3.8").unwrap();
+        assert_eq!(take_run_options(&mut opts).unwrap(), RunOptions::default());
+        assert_eq!(opts, hashmap!["version" => "3.8".into()]);
+    }
+
+    #[test]
+    fn test_output_mode() {
+        let mut opts = parse_options("output=split").unwrap();
+        assert_eq!(
+            take_run_options(&mut opts).unwrap().output,
+            OutputMode::Split,
+        );
+        let mut opts = parse_options("output=stdout").unwrap();
+        assert_eq!(
+            take_run_options(&mut opts).unwrap().output,
+            OutputMode::Stdout,
+        );
+        let mut opts = parse_options("output=hex").unwrap();
+        assert_eq!(take_run_options(&mut opts).unwrap().output, OutputMode::Hex);
+        let mut opts = parse_options("output=both").unwrap();
+        if let Ok(v) = take_run_options(&mut opts) {
+            panic!("{:?}", v);
+        }
+    }
+
+    #[test]
+    fn test_nested_env() {
+        let mut opts = parse_options("env.A.B=1").unwrap();
+        if let Ok(v) = take_run_options(&mut opts) {
+            panic!("{:?}", v);
+        }
+    }
 }
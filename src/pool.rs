@@ -0,0 +1,155 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use tokio::sync::Notify;
+
+use crate::runner::{Phase, RunSpec};
+
+/// Containers created ahead of time for popular languages, so that their runs can skip straight to
+/// starting them. Each one is used by a single run and then removed like any other
+#[derive(Debug, Default)]
+pub struct WarmPool {
+    /// Each language's default spec, and how many of its containers to keep ready
+    targets: Vec<(RunSpec, usize)>,
+    /// By image
+    ready: Mutex<HashMap<String, Vec<Warm>>>,
+    taken: Notify,
+}
+
+/// A working directory holder and the container for a run's first phase, created but not started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warm {
+    pub holder_id: String,
+    pub container_id: String,
+}
+
+impl WarmPool {
+    pub fn new(targets: Vec<(RunSpec, usize)>) -> Self {
+        Self {
+            targets,
+            ..Default::default()
+        }
+    }
+
+    pub fn targets(&self) -> &[(RunSpec, usize)] {
+        &self.targets
+    }
+
+    /// The phase pooled containers are created for: the compiler, or else the program without any
+    /// arguments, environment variables or stdin
+    pub fn first_phase(spec: &RunSpec) -> Phase<'_> {
+        let cmd = spec.compile.as_ref().unwrap_or(&spec.run);
+        Phase {
+            cmd: cmd.iter().map(String::as_str).collect(),
+            env: &[],
+            stdin: None,
+            // Only matters once the container starts
+            timeout: Default::default(),
+            compiling: spec.compile.is_some(),
+        }
+    }
+
+    /// A ready container for `spec`, if `phase` is the one it was created for
+    pub fn take(&self, spec: &RunSpec, phase: &Phase<'_>) -> Option<Warm> {
+        let pooled = Self::first_phase(spec);
+        if phase.cmd != pooled.cmd || !phase.env.is_empty() || phase.stdin.is_some() {
+            return None;
+        }
+        let warm = self.ready.lock().unwrap().get_mut(&spec.image())?.pop()?;
+        self.taken.notify_one();
+        Some(warm)
+    }
+
+    pub fn put(&self, spec: &RunSpec, warm: Warm) {
+        let mut ready = self.ready.lock().unwrap();
+        ready.entry(spec.image()).or_default().push(warm);
+    }
+
+    pub fn len(&self, spec: &RunSpec) -> usize {
+        let ready = self.ready.lock().unwrap();
+        ready.get(&spec.image()).map_or(0, Vec::len)
+    }
+
+    /// Waits until a container has been taken since the last time this returned
+    pub async fn wait_for_take(&self) {
+        self.taken.notified().await
+    }
+
+    /// Empties the pool so that nothing else gets taken
+    pub fn drain(&self) -> Vec<Warm> {
+        let mut ready = self.ready.lock().unwrap();
+        ready.drain().flat_map(|(_, warm)| warm).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(compile: Option<Vec<String>>) -> RunSpec {
+        RunSpec {
+            code_path: "main.x",
+            compile,
+            run: vec!["./main".to_owned()],
+            image_name: "x".to_owned(),
+            dockerfile: "FROM scratch\n".to_owned(),
+        }
+    }
+
+    fn warm(n: usize) -> Warm {
+        Warm {
+            holder_id: format!("holder-{}", n),
+            container_id: format!("container-{}", n),
+        }
+    }
+
+    #[test]
+    fn test_take() {
+        let spec = spec(None);
+        let pool = WarmPool::new(vec![]);
+        let phase = WarmPool::first_phase(&spec);
+        assert_eq!(pool.take(&spec, &phase), None);
+
+        pool.put(&spec, warm(1));
+        pool.put(&spec, warm(2));
+        assert_eq!(pool.len(&spec), 2);
+        // Anything the container wasn't created with means making a new one
+        let env = ["A=1".to_owned()];
+        for phase in [
+            Phase {
+                cmd: vec!["./main", "arg"],
+                ..WarmPool::first_phase(&spec)
+            },
+            Phase {
+                env: &env,
+                ..WarmPool::first_phase(&spec)
+            },
+            Phase {
+                stdin: Some("hi"),
+                ..WarmPool::first_phase(&spec)
+            },
+        ] {
+            assert_eq!(pool.take(&spec, &phase), None);
+        }
+        assert_eq!(pool.take(&spec, &phase), Some(warm(2)));
+        assert_eq!(pool.len(&spec), 1);
+        assert_eq!(pool.drain(), [warm(1)]);
+        assert_eq!(pool.take(&spec, &phase), None);
+    }
+
+    #[test]
+    fn test_take_compiled() {
+        let spec = spec(Some(vec!["cc".to_owned(), "main.x".to_owned()]));
+        let pool = WarmPool::new(vec![]);
+        pool.put(&spec, warm(1));
+        // The run phase is never pooled for compiled languages, whatever its arguments
+        let run = Phase {
+            cmd: vec!["./main"],
+            compiling: false,
+            ..WarmPool::first_phase(&spec)
+        };
+        assert_eq!(pool.take(&spec, &run), None);
+        let compile = WarmPool::first_phase(&spec);
+        assert_eq!(compile.cmd, ["cc", "main.x"]);
+        assert_eq!(pool.take(&spec, &compile), Some(warm(1)));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    convert::TryInto,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Tree,
+};
+
+/// A token bucket. Every run takes a token and tokens trickle back in over time
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Bucket {
+    /// How many runs can happen back to back
+    pub capacity: f64,
+    /// How long it takes to get one token back
+    pub refill_secs: f64,
+}
+
+impl Bucket {
+    /// Takes a token from a bucket last left in state `last`, returning its new state. If there
+    /// aren't any tokens, returns how long until there will be
+    fn take(&self, last: Option<BucketState>, now: u64) -> Result<BucketState, Duration> {
+        let tokens = match last {
+            Some(last) => {
+                let elapsed = now.saturating_sub(last.updated_ms) as f64 / 1000.0;
+                (last.tokens + elapsed / self.refill_secs).min(self.capacity)
+            }
+            None => self.capacity,
+        };
+        if tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - tokens) * self.refill_secs));
+        }
+        Ok(BucketState {
+            tokens: tokens - 1.0,
+            updated_ms: now,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RateLimitConfig {
+    pub user: Option<Bucket>,
+    pub channel: Option<Bucket>,
+    pub guild: Option<Bucket>,
+    /// Users who are never rate limited
+    #[serde(default)]
+    pub admins: Vec<UserId>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Scope {
+    User,
+    Channel,
+    Guild,
+}
+
+impl Scope {
+    fn prefix(self) -> u8 {
+        match self {
+            Scope::User => b'u',
+            Scope::Channel => b'c',
+            Scope::Guild => b'g',
+        }
+    }
+}
+
+/// Why a run wasn't allowed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limited {
+    pub scope: Scope,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let who = match self.scope {
+            Scope::User => "You're",
+            Scope::Channel => "This channel is",
+            Scope::Guild => "This server is",
+        };
+        write!(
+            f,
+            "{} running code too often. Please try again in {} seconds.",
+            who,
+            self.retry_after.as_secs() + 1,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BucketState {
+    tokens: f64,
+    updated_ms: u64,
+}
+
+impl BucketState {
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.tokens.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.updated_ms.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            tokens: f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            updated_ms: u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
+/// Token buckets for each user, channel and guild, kept in sled so that restarting doesn't reset
+/// them
+#[derive(Debug)]
+pub struct RateLimits {
+    tree: Tree,
+    conf: RateLimitConfig,
+}
+
+impl RateLimits {
+    pub fn new(tree: Tree, conf: RateLimitConfig) -> Self {
+        Self { tree, conf }
+    }
+
+    /// Takes a token from every bucket the run falls under, or from none of them if any is empty
+    pub fn check(
+        &self,
+        user: UserId,
+        channel: ChannelId,
+        guild: Option<GuildId>,
+    ) -> sled::Result<Result<(), Limited>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        self.check_at(user, channel, guild, now)
+    }
+
+    fn check_at(
+        &self,
+        user: UserId,
+        channel: ChannelId,
+        guild: Option<GuildId>,
+        now: u64,
+    ) -> sled::Result<Result<(), Limited>> {
+        if self.conf.admins.contains(&user) {
+            return Ok(Ok(()));
+        }
+
+        let buckets = [
+            (Scope::User, Some(user.0), self.conf.user),
+            (Scope::Channel, Some(channel.0), self.conf.channel),
+            (Scope::Guild, guild.map(|g| g.0), self.conf.guild),
+        ];
+        let result = self.tree.transaction(|tx| {
+            for (scope, id, bucket) in buckets {
+                let (id, bucket) = match (id, bucket) {
+                    (Some(id), Some(bucket)) => (id, bucket),
+                    _ => continue,
+                };
+                let mut key = vec![scope.prefix()];
+                key.extend_from_slice(&id.to_le_bytes());
+                let last = tx.get(&key)?.map(|v| BucketState::from_bytes(&v));
+                match bucket.take(last, now) {
+                    Ok(state) => {
+                        tx.insert(key, &state.to_bytes())?;
+                    }
+                    Err(retry_after) => {
+                        return Err(ConflictableTransactionError::Abort(Limited {
+                            scope,
+                            retry_after,
+                        }))
+                    }
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(Ok(())),
+            Err(TransactionError::Abort(limited)) => Ok(Err(limited)),
+            Err(TransactionError::Storage(err)) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUCKET: Bucket = Bucket {
+        capacity: 2.0,
+        refill_secs: 10.0,
+    };
+
+    fn rate_limits(conf: RateLimitConfig) -> RateLimits {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        RateLimits::new(db.open_tree("rate_limits").unwrap(), conf)
+    }
+
+    #[test]
+    fn test_bucket() {
+        let state = BUCKET.take(None, 0).unwrap();
+        assert_eq!(state.tokens, 1.0);
+        let state = BUCKET.take(Some(state), 0).unwrap();
+        assert_eq!(state.tokens, 0.0);
+        assert_eq!(BUCKET.take(Some(state), 5_000), Err(Duration::from_secs(5)));
+        let state = BUCKET.take(Some(state), 10_000).unwrap();
+        assert_eq!(state.tokens, 0.0);
+        // Never more than the capacity
+        let state = BUCKET.take(Some(state), 1_000_000).unwrap();
+        assert_eq!(state.tokens, 1.0);
+    }
+
+    #[test]
+    fn test_user_limit() {
+        let limits = rate_limits(RateLimitConfig {
+            user: Some(BUCKET),
+            ..Default::default()
+        });
+        let (user, channel) = (UserId(1), ChannelId(2));
+        assert_eq!(limits.check_at(user, channel, None, 0).unwrap(), Ok(()));
+        assert_eq!(limits.check_at(user, channel, None, 0).unwrap(), Ok(()));
+        assert_eq!(
+            limits.check_at(user, channel, None, 0).unwrap(),
+            Err(Limited {
+                scope: Scope::User,
+                retry_after: Duration::from_secs(10),
+            })
+        );
+        // Other users aren't affected
+        assert_eq!(
+            limits.check_at(UserId(3), channel, None, 0).unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            limits.check_at(user, channel, None, 10_000).unwrap(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_all_or_nothing() {
+        let limits = rate_limits(RateLimitConfig {
+            user: Some(BUCKET),
+            guild: Some(Bucket {
+                capacity: 1.0,
+                refill_secs: 10.0,
+            }),
+            ..Default::default()
+        });
+        let (user, channel, guild) = (UserId(1), ChannelId(2), Some(GuildId(3)));
+        assert_eq!(limits.check_at(user, channel, guild, 0).unwrap(), Ok(()));
+        assert_eq!(
+            limits
+                .check_at(user, channel, guild, 0)
+                .unwrap()
+                .unwrap_err()
+                .scope,
+            Scope::Guild
+        );
+        // The user's token wasn't spent on the failed run
+        assert_eq!(limits.check_at(user, channel, None, 0).unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn test_admin() {
+        let limits = rate_limits(RateLimitConfig {
+            user: Some(Bucket {
+                capacity: 0.0,
+                refill_secs: 10.0,
+            }),
+            admins: vec![UserId(1)],
+            ..Default::default()
+        });
+        assert_eq!(
+            limits.check_at(UserId(1), ChannelId(2), None, 0).unwrap(),
+            Ok(())
+        );
+        assert!(limits
+            .check_at(UserId(4), ChannelId(2), None, 0)
+            .unwrap()
+            .is_err());
+    }
+}
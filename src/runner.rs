@@ -1,14 +1,42 @@
 use core::fmt;
-use std::{borrow::Cow, collections::HashMap, str, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    io::Read,
+    path::Path,
+    process, str,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use futures::{Stream, StreamExt};
+use futures::{stream, AsyncWriteExt as _, Stream, StreamExt};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
 use shiplift::{tty::TtyChunk, Docker};
-use tokio::{fs::File, io::AsyncWriteExt};
-use unicase::Ascii;
+use thiserror::Error;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc::Sender, watch},
+};
 
+#[cfg(test)]
 use crate::lang::LangRef;
+use crate::{
+    backend::Backend,
+    docker_api::DockerApi,
+    lang::OptionsError,
+    options_parser::RunOptions,
+    pool::{Warm, WarmPool},
+};
 
 pub trait Loggable<'a> {
     type Log: fmt::Display;
@@ -30,311 +58,2462 @@ impl<'a> Loggable<'a> for shiplift::Container<'a> {
     }
 }
 
-#[derive(Debug)]
-pub struct UnrecognizedContainer;
+/// Whatever a backend's underlying failure was
+pub type ErrorSource = Box<dyn std::error::Error + Send + Sync>;
 
-impl fmt::Display for UnrecognizedContainer {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "unrecognized container")
-    }
+/// What can go wrong while building or running code
+#[derive(Debug, Error)]
+pub enum RunError {
+    /// The image needs to be built before it can be run
+    #[error("the image hasn't been built")]
+    ImageMissing,
+    #[error("failed to build the image: {0}")]
+    Build(#[source] ErrorSource),
+    /// The daemon (or whatever else runs our containers) didn't respond or refused a request
+    #[error("failed to reach the container runtime: {0}")]
+    Daemon(#[source] ErrorSource),
+    /// Getting the code into or the results out of a container failed
+    #[error("failed to copy files: {0}")]
+    Copy(#[source] ErrorSource),
+    /// The code ran, but its container couldn't be removed
+    #[error("failed to clean up: {0}")]
+    Cleanup(#[source] ErrorSource),
+    /// We've stopped starting new containers
+    #[error("shutting down")]
+    ShuttingDown,
+    /// Some of the requested `Hardening` can't be applied
+    #[error("hardening isn't available: {0}")]
+    Unhardened(String),
+    /// The backend can't keep interpreters running between runs
+    #[error("sessions aren't supported")]
+    SessionsUnsupported,
+    /// The session exited, was ended or never existed
+    #[error("the session isn't running")]
+    SessionEnded,
+    /// The code and stdin wouldn't fit in the working directory
+    #[error("the code and stdin are {0} bytes, more than the working directory holds")]
+    TooBig(u64),
 }
 
-impl std::error::Error for UnrecognizedContainer {}
+impl RunError {
+    pub fn build(err: impl Into<ErrorSource>) -> Self {
+        RunError::Build(err.into())
+    }
+
+    pub fn daemon(err: impl Into<ErrorSource>) -> Self {
+        RunError::Daemon(err.into())
+    }
+
+    pub fn copy(err: impl Into<ErrorSource>) -> Self {
+        RunError::Copy(err.into())
+    }
+
+    pub fn cleanup(err: impl Into<ErrorSource>) -> Self {
+        RunError::Cleanup(err.into())
+    }
+
+    /// What to tell whoever wanted their code run. The details are only for our logs
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            RunError::ImageMissing => {
+                "The container for this language went missing. Please try again."
+            }
+            RunError::Build(_) => {
+                "I couldn't build the container for this language. Please let my maintainers know."
+            }
+            RunError::Daemon(_) => {
+                "I can't reach my containers right now. Please try again in a bit."
+            }
+            RunError::Copy(_) => {
+                "I couldn't move your files in or out of the container. Please try again."
+            }
+            RunError::Cleanup(_) => {
+                "Something went wrong cleaning up after your code. Please try again."
+            }
+            RunError::ShuttingDown => "I'm restarting. Please try again in a bit.",
+            RunError::Unhardened(_) => {
+                "I can't run code safely right now. Please let my maintainers know."
+            }
+            RunError::SessionsUnsupported => "I can't keep sessions running here.",
+            RunError::SessionEnded => {
+                "Your session isn't running anymore. Start a new one with `#!session start`."
+            }
+            RunError::TooBig(_) => "Your code and input are too big for me to run.",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct RunSpec {
+    /// The file that gets run. Other source files are placed next to it
     pub code_path: &'static str,
+    /// Builds the program in its own container, for languages that need it. Whatever it leaves in
+    /// the working directory is carried over to `run`
+    pub compile: Option<Vec<String>>,
+    /// The command that runs the program. The program's arguments are appended to it
+    pub run: Vec<String>,
     pub image_name: String,
     pub dockerfile: String,
 }
 
+impl RunSpec {
+    /// The image built from `dockerfile`. It's tagged with a hash of the Dockerfile so that
+    /// changing the Dockerfile means building a new image
+    pub fn image(&self) -> String {
+        let hash = hex::encode(Sha1::digest(self.dockerfile.as_bytes()));
+        format!("{}:{}", self.repository(), &hash[..12])
+    }
+
+    fn repository(&self) -> String {
+        format!("codie/{}", self.image_name)
+    }
+}
+
+#[derive(Debug)]
+pub struct SourceFile<'a> {
+    /// Relative to the container's working directory
+    pub path: &'a str,
+    pub contents: &'a str,
+}
+
 pub struct DockerRunner {
     pub docker: Docker,
-    pub langs: HashMap<Ascii<&'static str>, LangRef>,
+    pub api: DockerApi,
     pub timeout: Duration,
+    pub compile_timeout: Duration,
     pub cpus: f64,
     pub memory_bytes: u64,
+    /// How much of each command's output to keep before killing it. Anything that doesn't fit in
+    /// a message is attached as a file
+    pub max_output_bytes: usize,
+    /// The size of the tmpfs that holds each run's working directory. Zero means no limit. What's
+    /// written to it counts against `memory_bytes`. Code and stdin bigger than it are turned away
+    pub workdir_bytes: u64,
+    /// Stops programs from writing anywhere but their working directory
+    pub read_only_rootfs: bool,
+    /// How many files to send back from each run's `OUTPUT_DIR`. Zero means no limit
+    pub max_files: usize,
+    /// How many bytes of files to send back from each run, in total. Zero means no limit
+    pub max_files_bytes: u64,
+    pub limits: ProcessLimits,
+    pub hardening: Hardening,
+    pub pool: WarmPool,
+    pub sessions: SessionContainers,
+    pub builds: InFlightBuilds,
+    pub run_ids: RunIds,
+    /// What `workdir_holder` is built from. Its default command has to keep running without any
+    /// input, like `pause` does. An image that's already on the host is used without pulling it
+    pub holder_image: String,
+}
+
+/// Keeps programs from making too many processes or files, like with a fork bomb. Zero means no
+/// limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ProcessLimits {
+    /// How many processes and threads each container can have at once
+    pub pids: u64,
+    /// How many files each process can have open
+    pub nofile: u64,
+    /// How many processes user "nobody" can have. The kernel counts every container's processes
+    /// together, so this should leave room for all the runs that happen at once
+    pub nproc: u64,
+    /// The biggest file a process can write, in bytes
+    pub fsize: u64,
+}
+
+impl Default for ProcessLimits {
+    fn default() -> Self {
+        Self {
+            pids: 256,
+            nofile: 4096,
+            nproc: 2048,
+            fsize: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl ProcessLimits {
+    /// The resource limits to set with `setrlimit`, by their names without the `RLIMIT_` prefix
+    pub fn rlimits(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("nofile", self.nofile),
+            ("nproc", self.nproc),
+            ("fsize", self.fsize),
+        ]
+        .into_iter()
+        .filter(|&(_, limit)| limit > 0)
+        .collect()
+    }
+}
+
+/// Extra confinement on top of running as nobody without capabilities. Nothing is on by default
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Hardening {
+    /// Replaces the daemon's default seccomp profile with the stricter one in `seccomp.json`,
+    /// which also blocks tracing other processes, keyrings, io_uring and NUMA policies
+    pub seccomp: bool,
+    /// Keeps setuid programs and file capabilities from granting more privileges
+    pub no_new_privileges: bool,
+    /// An AppArmor profile that's already loaded on the host
+    pub apparmor: Option<String>,
+    /// An SELinux label, like `type:container_t`
+    pub selinux_label: Option<String>,
+}
+
+impl Hardening {
+    /// Docker's `SecurityOpt`s for these
+    pub fn security_opts(&self) -> Vec<String> {
+        static SECCOMP_PROFILE: Lazy<String> = Lazy::new(|| {
+            let profile: serde_json::Value =
+                serde_json::from_str(include_str!("../seccomp.json")).unwrap();
+            profile.to_string()
+        });
+
+        let mut opts = Vec::new();
+        if self.seccomp {
+            opts.push(format!("seccomp={}", *SECCOMP_PROFILE));
+        }
+        if self.no_new_privileges {
+            opts.push("no-new-privileges:true".to_owned());
+        }
+        if let Some(profile) = &self.apparmor {
+            opts.push(format!("apparmor={}", profile));
+        }
+        if let Some(label) = &self.selinux_label {
+            opts.push(format!("label={}", label));
+        }
+        opts
+    }
+
+    /// The daemon features these need, as named in its `SecurityOptions`
+    fn features(&self) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if self.seccomp {
+            features.push("seccomp");
+        }
+        if self.apparmor.is_some() {
+            features.push("apparmor");
+        }
+        if self.selinux_label.is_some() {
+            features.push("selinux");
+        }
+        features
+    }
+}
+
+/// What `DockerRunner::holder_image` is unless it's configured
+pub const DEFAULT_HOLDER_IMAGE: &str = "registry.k8s.io/pause:3.9";
+
+/// Holds a run's working directory while its compile and run containers come and go. Files can't
+/// be copied into a tmpfs before its container starts, so this starts first and only sleeps
+fn workdir_holder(base: &str) -> RunSpec {
+    RunSpec {
+        code_path: "",
+        compile: None,
+        run: vec![],
+        image_name: "workdir".to_owned(),
+        dockerfile: format!("FROM {}\n", base),
+    }
+}
+
+/// Every container we create is labeled with this
+pub const RUN_ID_LABEL: &str = "codie.run_id";
+/// Every container we create is labeled with our instance name, so that we can find the ones we
+/// left behind without touching another bot's
+pub const INSTANCE_LABEL: &str = "codie.instance";
+
+/// The instance name when none is configured
+pub const DEFAULT_INSTANCE: &str = "codie";
+
+/// An instance name with characters that would make it ambiguous in labels or cgroup names
+#[derive(Debug, Error)]
+#[error("instance name {0:?} must be made of only letters, digits and underscores")]
+pub struct BadInstance(String);
+
+/// Hands out IDs for runs until we start shutting down
+#[derive(Debug)]
+pub struct RunIds {
+    instance: String,
+    next: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl Default for RunIds {
+    fn default() -> Self {
+        Self::new(DEFAULT_INSTANCE.to_owned()).unwrap()
+    }
+}
+
+impl RunIds {
+    pub fn new(instance: String) -> Result<Self, BadInstance> {
+        if instance.is_empty()
+            || !instance
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(BadInstance(instance));
+        }
+        Ok(Self {
+            instance,
+            next: Default::default(),
+            closed: Default::default(),
+        })
+    }
+
+    /// Which bot we are, for `INSTANCE_LABEL`
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// An ID to label a new container with, unless we're shutting down. It starts with
+    /// `instance` and a dash
+    pub fn next(&self) -> Result<String, RunError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(RunError::ShuttingDown);
+        }
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        Ok(format!("{}-{}-{}", self.instance, process::id(), n))
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The session containers that are running
+#[derive(Debug, Default)]
+pub struct SessionContainers(Mutex<HashMap<String, Arc<SessionContainer>>>);
+
+#[derive(Debug)]
+pub struct SessionContainer {
+    /// What the interpreter prints once it's done with a snippet
+    marker: String,
+    /// Held while a snippet runs, since the interpreter only takes one at a time
+    running: tokio::sync::Mutex<()>,
+}
+
+impl SessionContainers {
+    fn get(&self, id: &str) -> Option<Arc<SessionContainer>> {
+        self.0.lock().unwrap().get(id).cloned()
+    }
+
+    fn insert(&self, id: String, marker: String) {
+        let session = SessionContainer {
+            marker,
+            running: Default::default(),
+        };
+        self.0.lock().unwrap().insert(id, Arc::new(session));
+    }
+
+    fn remove(&self, id: &str) {
+        self.0.lock().unwrap().remove(id);
+    }
+}
+
+/// What running a snippet in a session did
+#[derive(Debug, Eq, PartialEq)]
+pub struct SessionRun {
+    pub output: Output,
+    /// Whether the session is gone now, like when the snippet timed out or killed the interpreter
+    pub ended: bool,
+}
+
+/// Images that are being built, so that concurrent requests for the same image share one build
+#[derive(Debug, Default)]
+pub struct InFlightBuilds(Mutex<HashMap<String, watch::Receiver<Option<BuildResult>>>>);
+
+/// Errors are stringified since waiters can't share a `RunError`
+type BuildResult = Result<(), String>;
+
+impl InFlightBuilds {
+    /// Runs `build` unless `image` is already being built, in which case waits for that build
+    pub async fn coalesce(
+        &self,
+        image: &str,
+        build: impl Future<Output = Result<(), RunError>>,
+    ) -> Result<(), RunError> {
+        let in_flight = {
+            let mut builds = self.0.lock().unwrap();
+            match builds.get(image) {
+                Some(rx) => Err(rx.clone()),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    builds.insert(image.to_owned(), rx);
+                    Ok(tx)
+                }
+            }
+        };
+        let tx = match in_flight {
+            Ok(tx) => tx,
+            Err(mut rx) => {
+                tracing::info!("Waiting on in-progress build of {}", image);
+                loop {
+                    if let Some(result) = rx.borrow().clone() {
+                        return result.map_err(RunError::build);
+                    }
+                    if rx.changed().await.is_err() {
+                        return Err(RunError::build(format!("build of {} was cancelled", image)));
+                    }
+                }
+            }
+        };
+
+        // Whether we finish or get cancelled, later requests should start their own build
+        struct Finished<'a>(&'a InFlightBuilds, &'a str);
+        impl Drop for Finished<'_> {
+            fn drop(&mut self) {
+                (self.0).0.lock().unwrap().remove(self.1);
+            }
+        }
+        let _finished = Finished(self, image);
+        let result = build.await;
+        let _ = tx.send(Some(
+            result.as_ref().map(|_| ()).map_err(|err| err.to_string()),
+        ));
+        result
+    }
 }
 
 impl fmt::Debug for DockerRunner {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("DockerRunner")
             .field("timeout", &self.timeout)
+            .field("compile_timeout", &self.compile_timeout)
             .field("cpus", &self.cpus)
             .field("memory_bytes", &self.memory_bytes)
+            .field("max_output_bytes", &self.max_output_bytes)
+            .field("workdir_bytes", &self.workdir_bytes)
+            .field("read_only_rootfs", &self.read_only_rootfs)
+            .field("max_files", &self.max_files)
+            .field("max_files_bytes", &self.max_files_bytes)
+            .field("limits", &self.limits)
+            .field("hardening", &self.hardening)
             .finish_non_exhaustive()
     }
 }
 
-impl DockerRunner {
-    pub fn get_lang_by_code(&self, code: &str) -> Option<LangRef> {
-        self.langs.get(&Ascii::new(code)).copied()
+#[serenity::async_trait]
+impl Backend for DockerRunner {
+    async fn build(&self, spec: &RunSpec) -> Result<(), RunError> {
+        self.build_holder().await?;
+        self.builds
+            .coalesce(&spec.image(), self.build_image(spec))
+            .await
     }
 
-    pub async fn build<'s>(&'s self, spec: &'s RunSpec) -> anyhow::Result<()> {
-        let dir = tempfile::tempdir()?;
+    async fn image_exists(&self, spec: &RunSpec) -> Result<bool, RunError> {
+        Ok(self.has_image(&spec.image()).await?
+            && self.has_image(&self.workdir_holder().image()).await?)
+    }
 
-        let file_path = dir.path().join("Dockerfile");
-        let mut file = File::create(file_path).await?;
-        file.write_all(spec.dockerfile.as_bytes()).await?;
-        file.flush().await?;
+    async fn prepare(&self) -> Result<(), RunError> {
+        self.build_holder().await
+    }
 
-        let dir_str = dir.path().to_str().unwrap();
+    async fn check_hardening(&self) -> Result<(), RunError> {
+        let opts = self.hardening.security_opts();
+        if opts.is_empty() {
+            return Ok(());
+        }
+        let supported = self
+            .api
+            .security_options()
+            .await
+            .map_err(RunError::daemon)?;
+        for feature in self.hardening.features() {
+            let name = format!("name={}", feature);
+            if !supported
+                .iter()
+                .any(|opt| opt.split(',').any(|kv| kv == name))
+            {
+                return Err(RunError::Unhardened(format!(
+                    "the daemon doesn't support {}",
+                    feature
+                )));
+            }
+        }
 
-        let image_name = format!("codie/{}", spec.image_name);
-        tracing::info!("Building {}", image_name);
-        let images = self.docker.images();
-        let build_opts = shiplift::BuildOptions::builder(dir_str)
-            .tag(image_name)
+        // The daemon only loads profiles when a container starts, so start one to be sure
+        self.build_holder().await?;
+        let probe_opts = shiplift::ContainerOptions::builder(&self.workdir_holder().image())
+            .user("65534:65534")
+            .network_mode("none")
+            .stop_signal("SIGKILL")
             .build();
-        let mut stream = images.build(&build_opts);
-        while let Some(build_result) = stream.next().await {
-            match build_result {
-                Ok(output) => match output.get("error") {
-                    Some(_) => anyhow::bail!("build error: {:?}", output),
-                    None => tracing::debug!("{:?}", output),
-                },
-                Err(e) => anyhow::bail!("failed while building: {:?}", e),
+        let overrides = json!({
+            "Labels": {
+                RUN_ID_LABEL: self.run_ids.next()?,
+                INSTANCE_LABEL: self.run_ids.instance(),
+            },
+            "HostConfig": {"SecurityOpt": opts},
+        });
+        let probe = self.create_container(&probe_opts, overrides).await?;
+        let result = async {
+            probe.start().await.map_err(|err| {
+                RunError::Unhardened(format!("a container with them didn't start: {}", err))
+            })?;
+            let security = self
+                .api
+                .container_security(probe.id())
+                .await
+                .map_err(RunError::daemon)?;
+            let applied = security.host_config.security_opt.unwrap_or_default();
+            if let Some(missing) = opts.iter().find(|opt| !applied.contains(opt)) {
+                let name = missing.split('=').next().unwrap_or(missing);
+                return Err(RunError::Unhardened(format!("the daemon dropped {}", name)));
+            }
+            if let Some(profile) = &self.hardening.apparmor {
+                if security.app_armor_profile != *profile {
+                    return Err(RunError::Unhardened(format!(
+                        "the container got AppArmor profile {:?}",
+                        security.app_armor_profile
+                    )));
+                }
+            }
+            if self.hardening.selinux_label.is_some() && security.process_label.is_empty() {
+                return Err(RunError::Unhardened(
+                    "the container didn't get an SELinux label".to_owned(),
+                ));
             }
+            Ok(())
         }
-        Ok(())
+        .await;
+        self.remove_container(&probe, result).await
     }
 
-    pub async fn run_code<'s>(
-        &'s self,
-        spec: &'s RunSpec,
-        code: &'s str,
-    ) -> anyhow::Result<Output> {
-        // TODO: Restrict disk usage
-        let container_opts =
-            shiplift::ContainerOptions::builder(&format!("codie/{}", &spec.image_name))
-                // Run as user "nobody"
-                .user("65534:65534")
-                // Ensure that we are unprivileged
-                .capabilities(vec![])
-                .privileged(false)
-                // No internet access
-                .network_mode("none")
-                // Be in a safe directory
-                .working_dir("/tmp")
-                // Don't take too many resources
-                .cpus(self.cpus)
-                .memory(self.memory_bytes)
-                // Stop immediately
-                .stop_signal("SIGKILL")
-                .stop_timeout(Duration::from_nanos(0))
-                .build();
-        let container = match self.docker.containers().create(&container_opts).await {
-            Ok(response) => shiplift::Container::new(&self.docker, response.id),
-            Err(shiplift::Error::Fault { code, .. }) if code == 404 => {
-                return Err(UnrecognizedContainer.into());
-            }
-            Err(err) => return Err(err.into()),
+    fn close(&self) {
+        self.run_ids.close();
+        // They're removed along with everything else
+        self.pool.drain();
+    }
+
+    async fn remove_containers(&self) -> Result<usize, RunError> {
+        let opts = shiplift::ContainerListOptions::builder()
+            .all()
+            .filter(vec![shiplift::ContainerFilter::Label(
+                INSTANCE_LABEL.to_owned(),
+                self.run_ids.instance().to_owned(),
+            )])
+            .build();
+        let containers = self
+            .docker
+            .containers()
+            .list(&opts)
+            .await
+            .map_err(RunError::daemon)?;
+        for container in &containers {
+            self.docker
+                .containers()
+                .get(&container.id)
+                .remove(remove_opts())
+                .await
+                .map_err(RunError::cleanup)?;
+            tracing::info!("Removed container {}", container.id);
+        }
+        Ok(containers.len())
+    }
+
+    async fn run_code(
+        &self,
+        spec: &RunSpec,
+        files: &[SourceFile<'_>],
+        stdin: Option<&str>,
+        opts: &RunOptions,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Report, RunError> {
+        let contents = files.iter().map(|file| file.contents);
+        check_size(contents.chain(stdin), self.workdir_bytes)?;
+        let env: Vec<String> = opts
+            .env
+            .iter()
+            .map(|(name, val)| format!("{}={}", name, val))
+            .collect();
+        let compile = spec.compile.as_ref().map(|cmd| Phase {
+            cmd: cmd.iter().map(String::as_str).collect(),
+            env: &env,
+            stdin: None,
+            timeout: self.compile_timeout,
+            compiling: true,
+        });
+        let run = Phase {
+            cmd: spec
+                .run
+                .iter()
+                .chain(&opts.args)
+                .map(String::as_str)
+                .collect(),
+            env: &env,
+            stdin,
+            timeout: self.timeout,
+            compiling: false,
         };
-        container
-            .copy_file_into(format!("/tmp/{}", spec.code_path), code.as_bytes())
-            .await?;
 
-        tracing::info!("{} starting", container.as_log());
-        container.start().await?;
-
-        async fn stop_container(container: &shiplift::Container<'_>) {
-            match container.stop(Some(Duration::from_secs(0))).await {
-                Ok(()) => {}
-                // Means container is already stopped
-                Err(shiplift::Error::Fault { code, .. }) if code == 304 => {}
-                Err(err) => panic!("{}", err),
-            }
-        }
-        let mut output_builder = OutputBuilder::new(
-            container.logs(
-                &shiplift::LogsOptions::builder()
-                    .follow(true)
-                    .stdout(true)
-                    .stderr(true)
-                    .build(),
+        let first = compile.as_ref().unwrap_or(&run);
+        let (holder, mut warm) = match self.pool.take(spec, first) {
+            Some(warm) => (
+                shiplift::Container::new(&self.docker, warm.holder_id),
+                Some(shiplift::Container::new(&self.docker, warm.container_id)),
             ),
-        );
-        let run_fut = tokio::time::timeout(self.timeout, async {
-            if output_builder.extend().await.is_err() {
-                return Err(());
+            None => (self.create_holder().await?, None),
+        };
+        let result = self
+            .run_in_workdir(
+                &holder,
+                &mut warm,
+                spec,
+                files,
+                compile.as_ref(),
+                &run,
+                progress,
+            )
+            .await;
+        // The holder's volume can't be removed while another container still uses it
+        if let Some(unused) = warm {
+            if let Err(err) = self.remove_container(&unused, Ok(())).await {
+                tracing::warn!("{}", err);
             }
-            let exit = container.wait().await.unwrap();
-            Ok(exit)
-        });
-        let exit = match run_fut.await {
-            // Finished successfully within time
-            Ok(Ok(exit)) => {
-                tracing::info!("{} finished", container.as_log());
-                exit
-            }
-            Ok(Err(_overflowed)) => {
-                tracing::warn!(
-                    "{} force-stopping. Reason: overflowed output",
-                    container.as_log()
-                );
-                stop_container(&container).await;
-                container.wait().await?
-            }
-            // Timed out
-            Err(_elapsed) => {
-                tracing::warn!(
-                    "{} force-stopping. Reason: exceeded timeout",
-                    container.as_log()
-                );
-                stop_container(&container).await;
-                container.wait().await?
-            }
-        };
-
-        // We may have timed out earlier and have some logs left over. Since the container has
-        // stopped, we can safely try to get all remaining logs without missing any.
-        let _ = output_builder.extend().await;
-
-        container
-            .remove(shiplift::RmContainerOptions::builder().force(true).build())
-            .await?;
-        tracing::info!("{} removed", container.as_log());
-        Ok(Output {
-            status: exit.status_code,
-            tty: output_builder.build(),
-        })
+        }
+        self.remove_container(&holder, result).await
     }
-}
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Output {
-    pub status: u64,
-    pub tty: Box<str>,
-}
+    async fn fill_pool(&self) {
+        // How long to wait before trying again after failing to create containers
+        const RETRY: Duration = Duration::from_secs(60);
+        loop {
+            for (spec, target) in self.pool.targets() {
+                while self.pool.len(spec) < *target {
+                    match self.create_warm(spec).await {
+                        Ok(warm) => self.pool.put(spec, warm),
+                        Err(RunError::ShuttingDown) => return,
+                        Err(RunError::ImageMissing) => match self.build(spec).await {
+                            Ok(()) => continue,
+                            Err(err) => {
+                                tracing::warn!(
+                                    "Failed to build {} for the pool: {}",
+                                    spec.image(),
+                                    err
+                                );
+                                break;
+                            }
+                        },
+                        Err(err) => {
+                            tracing::warn!(
+                                "Failed to create a container for {}: {}",
+                                spec.image(),
+                                err
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = tokio::time::timeout(RETRY, self.pool.wait_for_take()).await;
+        }
+    }
 
-impl Output {
-    pub fn success(&self) -> bool {
-        self.status == 0
+    async fn start_session(&self, spec: &RunSpec, cmd: &[String]) -> Result<String, RunError> {
+        let run_id = self.run_ids.next()?;
+        // Only needs to be something that snippets won't print by accident
+        let marker = hex::encode(Sha1::digest(
+            format!("{} {:?}", run_id, SystemTime::now()).as_bytes(),
+        ));
+        let mut cmd: Vec<&str> = cmd.iter().map(String::as_str).collect();
+        cmd.push(&marker);
+        let container_opts = shiplift::ContainerOptions::builder(&spec.image())
+            .user("65534:65534")
+            .capabilities(vec![])
+            .privileged(false)
+            .network_mode("none")
+            .working_dir("/tmp")
+            .cmd(cmd)
+            .cpus(self.cpus)
+            .memory(self.memory_bytes)
+            .stop_signal("SIGKILL")
+            .stop_timeout(Duration::from_nanos(0))
+            // Snippets are written to the interpreter's stdin, which stays open between them
+            .attach_stdin(true)
+            .build();
+        let mut tmpfs_opts = "mode=1777".to_owned();
+        if self.workdir_bytes > 0 {
+            tmpfs_opts.push_str(&format!(",size={}", self.workdir_bytes));
+        }
+        // Nothing is copied in, so the working directory doesn't need a holder
+        let mut host_config = self.confinement();
+        host_config["Tmpfs"] = json!({"/tmp": tmpfs_opts});
+        let overrides = json!({
+            "StdinOnce": false,
+            "Labels": {RUN_ID_LABEL: run_id, INSTANCE_LABEL: self.run_ids.instance()},
+            "HostConfig": host_config,
+        });
+        let container = self.create_container(&container_opts, overrides).await?;
+        if let Err(err) = container.start().await {
+            return self
+                .remove_container(&container, Err(RunError::daemon(err)))
+                .await;
+        }
+        tracing::info!("{} started a session", container.as_log());
+        self.sessions.insert(container.id().to_owned(), marker);
+        Ok(container.id().to_owned())
     }
-}
 
-impl fmt::Display for Output {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Replace ``` with something that looks really similar
-        fn escape_codeblock(code: &str) -> Cow<str> {
-            static CODE_BLOCK_FENCE: Lazy<Regex> = Lazy::new(|| Regex::new(r"```").unwrap());
-            CODE_BLOCK_FENCE.replace_all(code, "\u{02CB}\u{02CB}\u{02CB}")
+    async fn run_in_session(
+        &self,
+        id: &str,
+        code: &str,
+        stdin: Option<&str>,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<SessionRun, RunError> {
+        check_size([code].into_iter().chain(stdin), self.workdir_bytes)?;
+        let session = self.sessions.get(id).ok_or(RunError::SessionEnded)?;
+        let _running = session.running.lock().await;
+        let container = shiplift::Container::new(&self.docker, id);
+        // It could have been killed for running out of memory since the last snippet
+        match container.inspect().await {
+            Ok(details) if details.state.running => {}
+            Ok(_) => {
+                self.end_session(id).await?;
+                return Err(RunError::SessionEnded);
+            }
+            Err(shiplift::Error::Fault { code, .. }) if code == 404 => {
+                self.sessions.remove(id);
+                return Err(RunError::SessionEnded);
+            }
+            Err(err) => return Err(RunError::daemon(err)),
         }
 
-        if !self.success() {
-            writeln!(f, "**EXIT STATUS:** {}", self.status)?;
+        let (logs, mut requests) = container.attach().await.map_err(RunError::daemon)?.split();
+        requests
+            .write_all(&session_request(code, stdin.unwrap_or_default()))
+            .await
+            .map_err(RunError::copy)?;
+        let phase = Phase {
+            cmd: vec![],
+            env: &[],
+            stdin: None,
+            timeout: self.timeout,
+            compiling: false,
+        };
+        let status = Mutex::new(None);
+        let finished = &status;
+        let container = &container;
+        let name = container.as_log();
+        let result = supervise(
+            &name,
+            Box::pin(until_marker(logs, &session.marker, finished)),
+            &phase,
+            self.max_output_bytes,
+            progress,
+            || async move {
+                let status = *finished.lock().unwrap();
+                match status {
+                    Some(status) => Ok(Exit::status(status)),
+                    None => {
+                        let exit = container.wait().await.map_err(RunError::daemon)?;
+                        Ok(Exit::status(exit.status_code))
+                    }
+                }
+            },
+            || stop_container(container),
+        )
+        .await;
+
+        let ended = status.lock().unwrap().is_none();
+        if !ended {
+            return result.map(|output| SessionRun { output, ended });
+        }
+        let mut output = match result {
+            Ok(output) => output,
+            Err(err) => return self.end_session(id).await.and(Err(err)),
+        };
+        if output.termination.maybe_out_of_memory() {
+            match container.inspect().await {
+                Ok(details) if details.state.oom_killed => {
+                    output.termination = Termination::OutOfMemory;
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!("Failed to inspect {}: {}", name, err),
+            }
         }
+        self.end_session(id).await?;
+        Ok(SessionRun { output, ended })
+    }
 
-        write!(f, "```\n{}```", escape_codeblock(&self.tty))?;
+    async fn end_session(&self, id: &str) -> Result<(), RunError> {
+        self.sessions.remove(id);
+        let container = shiplift::Container::new(&self.docker, id);
+        match container.remove(remove_opts()).await {
+            Ok(()) => tracing::info!("{} removed", container.as_log()),
+            Err(shiplift::Error::Fault { code, .. }) if code == 404 => {}
+            Err(err) => return Err(RunError::cleanup(err)),
+        }
         Ok(())
     }
 }
 
-struct OutputBuilder<S>
-where
-    S: Stream<Item = shiplift::Result<TtyChunk>> + Unpin,
-{
-    buf: Vec<u8>,
-    codepoints: usize,
-    logs: Option<S>,
-}
+impl DockerRunner {
+    async fn has_image(&self, image: &str) -> Result<bool, RunError> {
+        match self.docker.images().get(image).inspect().await {
+            Ok(_) => Ok(true),
+            Err(shiplift::Error::Fault { code, .. }) if code == 404 => Ok(false),
+            Err(err) => Err(RunError::daemon(err)),
+        }
+    }
 
-const MAX_OUTPUT_CODEPOINTS: usize = serenity::constants::MESSAGE_CODE_LIMIT as usize
-    - "mentions_cost_22_chars: **EXIT STATUS:** 255\n```...```".len();
+    fn workdir_holder(&self) -> RunSpec {
+        workdir_holder(&self.holder_image)
+    }
 
-impl<S> OutputBuilder<S>
-where
-    S: Stream<Item = shiplift::Result<TtyChunk>> + Unpin,
-{
-    fn new(logs: S) -> Self {
-        Self {
-            buf: Vec::new(),
-            codepoints: 0,
-            logs: Some(logs),
+    /// Builds the image for `workdir_holder` unless it's already there
+    async fn build_holder(&self) -> Result<(), RunError> {
+        let holder = self.workdir_holder();
+        if self.has_image(&holder.image()).await? {
+            return Ok(());
         }
+        self.builds
+            .coalesce(&holder.image(), self.build_image(&holder))
+            .await
     }
 
-    fn build(self) -> Box<str> {
-        String::from_utf8(self.buf).unwrap().into_boxed_str()
-    }
+    async fn build_image(&self, spec: &RunSpec) -> Result<(), RunError> {
+        let dir = tempfile::tempdir().map_err(RunError::build)?;
 
-    async fn extend(&mut self) -> Result<(), ()> {
-        let logs = match self.logs.as_mut() {
-            Some(logs) => logs,
-            None => return Err(()),
-        };
+        let file_path = dir.path().join("Dockerfile");
+        async {
+            let mut file = File::create(file_path).await?;
+            file.write_all(spec.dockerfile.as_bytes()).await?;
+            file.flush().await
+        }
+        .await
+        .map_err(RunError::build)?;
 
-        // TODO: Sometimes logs.next.await() == None even though not all the logs have been
-        // returned... I think docker is closing our connection incorrectly? See `test_no_newline`
-        while let Some(chunk) = logs.next().await {
-            match chunk.unwrap() {
-                TtyChunk::StdOut(ref mut bytes) | TtyChunk::StdErr(ref mut bytes) => {
-                    // If we can count the codepoints, count them appropriately. If we can't,
-                    // assume the worst case where each byte is a codepoint
-                    self.codepoints += match str::from_utf8(bytes) {
-                        Ok(s) => s.chars().count(),
-                        Err(_) => bytes.len(),
-                    };
-                    if self.codepoints > MAX_OUTPUT_CODEPOINTS {
-                        self.logs = None;
-                        self.buf.extend_from_slice(b"...");
-                        return Err(());
-                    }
-                    self.buf.append(bytes);
+        let dir_str = dir
+            .path()
+            .to_str()
+            .ok_or_else(|| RunError::build("the temporary directory's path isn't UTF-8"))?;
+
+        let image = spec.image();
+        tracing::info!("Building {}", image);
+        let images = self.docker.images();
+        let build_opts = shiplift::BuildOptions::builder(dir_str).tag(image).build();
+        let mut stream = images.build(&build_opts);
+        while let Some(build_result) = stream.next().await {
+            match build_result {
+                Ok(output) => match output.get("error") {
+                    Some(_) => return Err(RunError::build(format!("{:?}", output))),
+                    None => tracing::debug!("{:?}", output),
+                },
+                Err(err) => return Err(RunError::daemon(err)),
+            }
+        }
+
+        match self.stale_images(spec).await {
+            Ok(stale) => {
+                for image in stale {
+                    tracing::warn!("{} is stale and can be removed", image);
                 }
-                TtyChunk::StdIn(_) => unreachable!(),
             }
+            Err(err) => tracing::warn!("failed to look for stale images: {}", err),
         }
-        self.logs = None;
         Ok(())
     }
-}
 
-#[cfg(test)]
-pub(crate) async fn test_run(lang: LangRef, code: &str) -> anyhow::Result<Output> {
-    static TEST_RUNNER: once_cell::sync::Lazy<DockerRunner> =
-        once_cell::sync::Lazy::new(|| DockerRunner {
-            docker: Docker::new(),
-            timeout: Duration::from_secs(10),
-            // As much as needed
-            cpus: 0.0,
-            memory_bytes: 0,
-            langs: HashMap::new(),
+    /// Images built from old versions of `spec`'s Dockerfile
+    pub async fn stale_images(&self, spec: &RunSpec) -> Result<Vec<String>, RunError> {
+        let current = spec.image();
+        let prefix = format!("{}:", spec.repository());
+        let images = self
+            .docker
+            .images()
+            .list(&Default::default())
+            .await
+            .map_err(RunError::daemon)?;
+        Ok(images
+            .into_iter()
+            .flat_map(|image| image.repo_tags.unwrap_or_default())
+            .filter(|tag| tag.starts_with(&prefix) && *tag != current)
+            .collect())
+    }
+
+    /// Creates a container that holds a run's working directory. The working directory is a tmpfs
+    /// that outlives each of the run's other containers
+    async fn create_holder(&self) -> Result<shiplift::Container<'_>, RunError> {
+        let holder_opts = shiplift::ContainerOptions::builder(&self.workdir_holder().image())
+            .user("65534:65534")
+            .capabilities(vec![])
+            .privileged(false)
+            .network_mode("none")
+            .stop_signal("SIGKILL")
+            .stop_timeout(Duration::from_nanos(0))
+            .build();
+        let mut tmpfs_opts = "mode=1777".to_owned();
+        if self.workdir_bytes > 0 {
+            tmpfs_opts.push_str(&format!(",size={}", self.workdir_bytes));
+        }
+        let overrides = json!({
+            "Labels": {
+                RUN_ID_LABEL: self.run_ids.next()?,
+                INSTANCE_LABEL: self.run_ids.instance(),
+            },
+            "HostConfig": {
+                "ReadonlyRootfs": true,
+                "SecurityOpt": self.hardening.security_opts(),
+                // Anonymous, so that it's removed along with the container
+                "Mounts": [{
+                    "Type": "volume",
+                    "Target": "/tmp",
+                    "VolumeOptions": {
+                        "DriverConfig": {
+                            "Name": "local",
+                            "Options": {"type": "tmpfs", "device": "tmpfs", "o": tmpfs_opts},
+                        },
+                    },
+                }],
+            },
         });
+        self.create_container(&holder_opts, overrides).await
+    }
 
-    let spec = lang.run_spec(Default::default()).unwrap();
-    match TEST_RUNNER.run_code(&spec, code).await {
-        Ok(output) => Ok(output),
-        Err(err) => match err.downcast_ref::<UnrecognizedContainer>() {
-            Some(_) => {
-                TEST_RUNNER.build(&spec).await.unwrap();
-                TEST_RUNNER.run_code(&spec, code).await
-            }
-            None => Err(err),
-        },
+    /// Creates a holder and a container for `spec`'s first phase to go in the pool
+    async fn create_warm(&self, spec: &RunSpec) -> Result<Warm, RunError> {
+        let holder = self.create_holder().await?;
+        let phase = WarmPool::first_phase(spec);
+        match self.create_phase_container(spec, &phase, holder.id()).await {
+            Ok(container) => Ok(Warm {
+                holder_id: holder.id().to_owned(),
+                container_id: container.id().to_owned(),
+            }),
+            Err(err) => self.remove_container(&holder, Err(err)).await,
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::lang::*;
+    /// Copies `files` into the working directory held by `holder`, then compiles and runs them.
+    /// `warm` is taken for the first phase if there is one
+    #[allow(clippy::too_many_arguments)]
+    async fn run_in_workdir<'s>(
+        &'s self,
+        holder: &shiplift::Container<'_>,
+        warm: &mut Option<shiplift::Container<'s>>,
+        spec: &RunSpec,
+        files: &[SourceFile<'_>],
+        compile: Option<&Phase<'_>>,
+        run: &Phase<'_>,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Report, RunError> {
+        holder.start().await.map_err(RunError::daemon)?;
+        for file in files {
+            holder
+                .copy_file_into(format!("/tmp/{}", file.path), file.contents.as_bytes())
+                .await
+                .map_err(RunError::copy)?;
+        }
+        holder
+            .copy_to(Path::new("/tmp"), output_dir_archive().into())
+            .await
+            .map_err(RunError::copy)?;
 
-    #[tokio::test]
-    async fn test_timeout() {
-        let output = test_run(&Python, "while True: pass").await.unwrap();
-        assert_eq!(
-            output,
-            Output {
-                // The status Python returns from SIGKILL
-                status: 137,
-                tty: "".into(),
+        let compile = match compile {
+            Some(phase) => {
+                // Whatever the compiler leaves behind is what we run
+                let output = self
+                    .run_container(spec, phase, holder.id(), warm.take(), progress)
+                    .await?;
+                if !output.success() {
+                    return Ok(Report {
+                        compile: Some(output),
+                        run: None,
+                    });
+                }
+                Some(output)
             }
-        );
+            None => None,
+        };
+
+        let mut run = self
+            .run_container(spec, run, holder.id(), warm.take(), progress)
+            .await?;
+        run.files = self.output_files(holder).await?;
+        Ok(Report {
+            compile,
+            run: Some(run),
+        })
     }
 
-    #[tokio::test]
+    /// Copies out what the run left in `OUTPUT_DIR`, stopping once there's more than we'd keep
+    async fn output_files(
+        &self,
+        holder: &shiplift::Container<'_>,
+    ) -> Result<OutputFiles, RunError> {
+        // Room for the archive's headers and padding
+        const SLACK: u64 = 1024 * 1024;
+        let mut archive = Vec::new();
+        let mut stopped = false;
+        let chunks = holder.copy_from(Path::new(&format!("/tmp/{}", OUTPUT_DIR)));
+        tokio::pin!(chunks);
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => archive.extend_from_slice(&chunk),
+                // The program removed it
+                Err(shiplift::Error::Fault { code, .. }) if code == 404 => {
+                    return Ok(Default::default())
+                }
+                Err(err) => return Err(RunError::copy(err)),
+            }
+            if self.max_files_bytes > 0 && archive.len() as u64 > self.max_files_bytes + SLACK {
+                stopped = true;
+                break;
+            }
+        }
+        let mut files = OutputFiles::from_archive(&archive, self.max_files, self.max_files_bytes);
+        files.truncated |= stopped;
+        Ok(files)
+    }
+
+    /// Runs a single command in `container`, or else a fresh container, using the working directory
+    /// held by `holder_id`
+    async fn run_container(
+        &self,
+        spec: &RunSpec,
+        phase: &Phase<'_>,
+        holder_id: &str,
+        container: Option<shiplift::Container<'_>>,
+        progress: Option<&Sender<Progress>>,
+    ) -> Result<Output, RunError> {
+        let container = match container {
+            Some(container) => container,
+            None => self.create_phase_container(spec, phase, holder_id).await?,
+        };
+        // Whatever happens, the container shouldn't outlive the run
+        let result = self.start_container(&container, phase, progress).await;
+        self.remove_container(&container, result).await
+    }
+
+    async fn create_phase_container(
+        &self,
+        spec: &RunSpec,
+        phase: &Phase<'_>,
+        holder_id: &str,
+    ) -> Result<shiplift::Container<'_>, RunError> {
+        let container_opts = shiplift::ContainerOptions::builder(&spec.image())
+            // Run as user "nobody"
+            .user("65534:65534")
+            // Ensure that we are unprivileged
+            .capabilities(vec![])
+            .privileged(false)
+            // No internet access
+            .network_mode("none")
+            // Be in a safe directory
+            .working_dir("/tmp")
+            .cmd(phase.cmd.clone())
+            .env(phase.env)
+            // Don't take too many resources
+            .cpus(self.cpus)
+            .memory(self.memory_bytes)
+            // Stop immediately
+            .stop_signal("SIGKILL")
+            .stop_timeout(Duration::from_nanos(0))
+            // Only give the program a stdin if we have something to feed it
+            .attach_stdin(phase.stdin.is_some())
+            .build();
+        let mut host_config = self.confinement();
+        // Mounts the working directory at /tmp
+        host_config["VolumesFrom"] = json!([holder_id]);
+        let overrides = json!({
+            // Close the program's stdin once we've finished writing to it
+            "StdinOnce": phase.stdin.is_some(),
+            "Labels": {
+                RUN_ID_LABEL: self.run_ids.next()?,
+                INSTANCE_LABEL: self.run_ids.instance(),
+            },
+            "HostConfig": host_config,
+        });
+        self.create_container(&container_opts, overrides).await
+    }
+
+    /// The `HostConfig` that keeps code in line, on top of what `ContainerOptions` sets
+    fn confinement(&self) -> serde_json::Value {
+        json!({
+            "ReadonlyRootfs": self.read_only_rootfs,
+            "SecurityOpt": self.hardening.security_opts(),
+            "PidsLimit": self.limits.pids,
+            "Ulimits": self
+                .limits
+                .rlimits()
+                .into_iter()
+                .map(|(name, limit)| json!({"Name": name, "Soft": limit, "Hard": limit}))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    async fn create_container(
+        &self,
+        opts: &shiplift::ContainerOptions,
+        overrides: serde_json::Value,
+    ) -> Result<shiplift::Container<'_>, RunError> {
+        match self.api.create_container(opts, overrides).await {
+            Ok(id) => Ok(shiplift::Container::new(&self.docker, id)),
+            Err(shiplift::Error::Fault { code, .. }) if code == 404 => Err(RunError::ImageMissing),
+            Err(err) => Err(RunError::daemon(err)),
+        }
+    }
+
+    /// Force-removes `container` once it's done with, passing `result` through unless that fails
+    async fn remove_container<T>(
+        &self,
+        container: &shiplift::Container<'_>,
+        result: Result<T, RunError>,
+    ) -> Result<T, RunError> {
+        match container.remove(remove_opts()).await {
+            Ok(()) => tracing::info!("{} removed", container.as_log()),
+            // Reporting the run's own error is more useful
+            Err(err) if result.is_err() => {
+                tracing::warn!("Failed to remove {}: {}", container.as_log(), err)
+            }
+            Err(err) => return Err(RunError::cleanup(err)),
+        }
+        result
+    }
+
+    async fn start_container<'s>(
+        &'s self,
+        container: &'s shiplift::Container<'s>,
+        phase: &'s Phase<'s>,
+        progress: Option<&'s Sender<Progress>>,
+    ) -> Result<Output, RunError> {
+        // We attach before starting so that we don't miss any output
+        let (logs, mut stdin_writer) = container.attach().await.map_err(RunError::daemon)?.split();
+        if let Some(stdin) = phase.stdin {
+            stdin_writer
+                .write_all(stdin.as_bytes())
+                .await
+                .map_err(RunError::copy)?;
+            stdin_writer.close().await.map_err(RunError::copy)?;
+        }
+
+        tracing::info!("{} starting", container.as_log());
+        container.start().await.map_err(RunError::daemon)?;
+
+        let name = container.as_log();
+        let supervised = supervise(
+            &name,
+            logs,
+            phase,
+            self.max_output_bytes,
+            progress,
+            || async move {
+                // Docker reports death by signal N as 128 + N, the same as exiting with it
+                let exit = container.wait().await.map_err(RunError::daemon)?;
+                Ok(Exit::status(exit.status_code))
+            },
+            || stop_container(container),
+        );
+        // Missing stats shouldn't stop the run. Docker only has stats for running containers, and
+        // only about once a second, so there's no last look once it exits. See `Usage`
+        let samples = stream::once(self.api.stats(container.id()))
+            .filter_map(|stats| async move {
+                stats
+                    .map_err(|err| tracing::warn!("Failed to get stats: {}", err))
+                    .ok()
+            })
+            .flatten()
+            .filter_map(|stats| async move {
+                let stats = stats.ok()?;
+                Some(Sample {
+                    cpu: Duration::from_nanos(stats.cpu_stats.cpu_usage.total_usage),
+                    memory_bytes: stats.memory_stats.working_set(),
+                })
+            });
+        let mut output = with_usage(supervised, samples).await?;
+        if output.termination.maybe_out_of_memory() {
+            match container.inspect().await {
+                Ok(details) if details.state.oom_killed => {
+                    output.termination = Termination::OutOfMemory;
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!("Failed to inspect {}: {}", name, err),
+            }
+        }
+        Ok(output)
+    }
+}
+
+async fn stop_container(container: &shiplift::Container<'_>) -> Result<(), RunError> {
+    match container.stop(Some(Duration::from_secs(0))).await {
+        Ok(()) => Ok(()),
+        // Means container is already stopped
+        Err(shiplift::Error::Fault { code, .. }) if code == 304 => Ok(()),
+        Err(err) => Err(RunError::daemon(err)),
+    }
+}
+
+/// A tarball with just `OUTPUT_DIR`, owned by the user code runs as
+fn output_dir_archive() -> Vec<u8> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o755);
+    header.set_uid(65534);
+    header.set_gid(65534);
+    header.set_size(0);
+    let mut archive = tar::Builder::new(Vec::new());
+    // Writing to a Vec can't fail
+    archive
+        .append_data(&mut header, OUTPUT_DIR, std::io::empty())
+        .unwrap();
+    archive.into_inner().unwrap()
+}
+
+/// Turns away input that adds up to more than `max_bytes`, before any containers are made for it.
+/// Zero means no limit
+fn check_size<'a>(input: impl Iterator<Item = &'a str>, max_bytes: u64) -> Result<(), RunError> {
+    let bytes: u64 = input.map(|text| text.len() as u64).sum();
+    if max_bytes > 0 && bytes > max_bytes {
+        return Err(RunError::TooBig(bytes));
+    }
+    Ok(())
+}
+
+/// Also removes the container's anonymous volumes, like the one holding the working directory
+fn remove_opts() -> shiplift::RmContainerOptions {
+    shiplift::RmContainerOptions::builder()
+        .force(true)
+        .volumes(true)
+        .build()
+}
+
+/// Collects a started command's output until it exits, `kill`ing it if it runs for too long or
+/// prints too much
+pub(crate) async fn supervise<S, W, K>(
+    name: &(dyn fmt::Display + Sync),
+    logs: S,
+    phase: &Phase<'_>,
+    max_bytes: usize,
+    progress: Option<&Sender<Progress>>,
+    mut wait: impl FnMut() -> W,
+    kill: impl FnOnce() -> K,
+) -> Result<Output, RunError>
+where
+    S: Stream<Item = shiplift::Result<TtyChunk>> + Unpin,
+    W: Future<Output = Result<Exit, RunError>>,
+    K: Future<Output = Result<(), RunError>>,
+{
+    let started = Instant::now();
+    let mut output_builder = OutputBuilder::new(logs, max_bytes);
+    let run_fut = tokio::time::timeout(phase.timeout, async {
+        // Quick programs finish before anyone would want to look at them
+        let mut ticks = tokio::time::interval_at(
+            tokio::time::Instant::now() + PROGRESS_INTERVAL,
+            PROGRESS_INTERVAL,
+        );
+        loop {
+            tokio::select! {
+                extended = output_builder.extend() => match extended? {
+                    Extended::Finished => break,
+                    Extended::Overflowed => return Ok(None),
+                },
+                _ = ticks.tick(), if progress.is_some() => {
+                    // Nobody should wait on a slow reader, so we skip snapshots it can't take
+                    let _ = progress.unwrap().try_send(Progress {
+                        compiling: phase.compiling,
+                        tty: output_builder.snapshot(),
+                        elapsed: started.elapsed(),
+                    });
+                }
+            }
+        }
+        wait().await.map(Some)
+    });
+    let (status, termination) = match run_fut.await {
+        // Finished successfully within time
+        Ok(Ok(Some(exit))) => {
+            tracing::info!("{} finished", name);
+            (exit.status, Termination::from_exit(exit))
+        }
+        // We can't watch it anymore, so it can't keep running
+        Ok(Err(err)) => {
+            tracing::warn!("{} force-stopping. Reason: {}", name, err);
+            if let Err(kill_err) = kill().await {
+                tracing::warn!("Failed to stop {}: {}", name, kill_err);
+            }
+            return Err(err);
+        }
+        Ok(Ok(None)) => {
+            tracing::warn!("{} force-stopping. Reason: overflowed output", name);
+            kill().await?;
+            (wait().await?.status, Termination::OutputOverflow(max_bytes))
+        }
+        // Timed out
+        Err(_elapsed) => {
+            tracing::warn!("{} force-stopping. Reason: exceeded timeout", name);
+            kill().await?;
+            (wait().await?.status, Termination::TimedOut(phase.timeout))
+        }
+    };
+
+    let wall = started.elapsed();
+
+    // We may have timed out earlier and have some logs left over. Since the command has stopped,
+    // we can safely try to get all remaining logs without missing any.
+    output_builder.extend().await?;
+
+    Ok(Output {
+        status,
+        termination,
+        tty: output_builder.build(),
+        usage: Some(Usage {
+            wall,
+            ..Default::default()
+        }),
+        files: Default::default(),
+    })
+}
+
+/// Fills in the CPU time and peak memory of `run`'s output from `samples` taken while it runs.
+/// Samples that haven't come in by the time it finishes are missed, and without any, only the
+/// wall time is known
+pub(crate) async fn with_usage<R, S>(run: R, samples: S) -> Result<Output, RunError>
+where
+    R: Future<Output = Result<Output, RunError>>,
+    S: Stream<Item = Sample>,
+{
+    tokio::pin!(run, samples);
+    let mut usage = Usage::default();
+    let mut output = loop {
+        tokio::select! {
+            output = &mut run => break output?,
+            Some(sample) = samples.next() => usage.record(sample),
+        }
+    };
+    if let Some(output_usage) = &mut output.usage {
+        output_usage.cpu = usage.cpu;
+        output_usage.peak_memory_bytes = usage.peak_memory_bytes;
+    }
+    Ok(output)
+}
+
+/// Reads `reader` until it closes, tagging everything with `tag`
+pub(crate) fn read_chunks<R>(
+    reader: R,
+    tag: fn(Vec<u8>) -> TtyChunk,
+) -> impl Stream<Item = shiplift::Result<TtyChunk>>
+where
+    R: AsyncRead + Unpin,
+{
+    stream::unfold(reader, move |mut reader| async move {
+        let mut buf = vec![0; 8192];
+        match reader.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(tag(buf)), reader))
+            }
+            Err(err) => Some((Err(shiplift::Error::IO(err)), reader)),
+        }
+    })
+    .take_while(|chunk| futures::future::ready(chunk.is_ok()))
+}
+
+/// What to write to a session's interpreter to run `code`. Interpreters read a line with the
+/// length in bytes of the code and then of its stdin, separated by a space, followed by the code
+/// and the stdin themselves. Once the code is done, they print their marker argument and the
+/// code's exit status on a line to both stdout and stderr
+fn session_request(code: &str, stdin: &str) -> Vec<u8> {
+    let mut request = format!("{} {}\n", code.len(), stdin.len()).into_bytes();
+    request.extend_from_slice(code.as_bytes());
+    request.extend_from_slice(stdin.as_bytes());
+    request
+}
+
+/// Passes a session's output through until the interpreter says that the snippet is done, storing
+/// the snippet's exit status in `status`. Ends early if the interpreter does
+fn until_marker<'a, S>(
+    logs: S,
+    marker: &'a str,
+    status: &'a Mutex<Option<u64>>,
+) -> impl Stream<Item = shiplift::Result<TtyChunk>> + Send + 'a
+where
+    S: Stream<Item = shiplift::Result<TtyChunk>> + Unpin + Send + 'a,
+{
+    let output = SnippetOutput::new(marker.as_bytes());
+    stream::unfold(Some((logs, output)), move |state| async move {
+        let (mut logs, mut output) = state?;
+        loop {
+            if let Some(finished) = output.status() {
+                *status.lock().unwrap() = Some(finished);
+                return None;
+            }
+            match logs.next().await {
+                Some(Ok(chunk)) => {
+                    let chunks = output.push(chunk);
+                    if !chunks.is_empty() {
+                        return Some((chunks, Some((logs, output))));
+                    }
+                }
+                Some(Err(err)) => {
+                    tracing::warn!("Failed to read a session's output: {}", err);
+                    return Some((output.finish(), None));
+                }
+                None => return Some((output.finish(), None)),
+            }
+        }
+    })
+    .flat_map(|chunks| stream::iter(chunks.into_iter().map(Ok)))
+}
+
+/// Splits a session's output at the marker lines that end each stream
+struct SnippetOutput<'a> {
+    marker: &'a [u8],
+    /// Stdout then stderr. What could still turn out to be part of a marker line
+    pending: [Vec<u8>; 2],
+    done: [bool; 2],
+    status: Option<u64>,
+}
+
+impl<'a> SnippetOutput<'a> {
+    fn new(marker: &'a [u8]) -> Self {
+        Self {
+            marker,
+            pending: Default::default(),
+            done: [false; 2],
+            status: None,
+        }
+    }
+
+    /// The snippet's exit status, once both streams have ended
+    fn status(&self) -> Option<u64> {
+        // An interpreter that can't say how the snippet went counts it as a failure
+        (self.done.iter().all(|&done| done)).then(|| self.status.unwrap_or(1))
+    }
+
+    /// Takes in `chunk`, returning what of it is known to be the snippet's output
+    fn push(&mut self, chunk: TtyChunk) -> Vec<TtyChunk> {
+        let (i, bytes) = match chunk {
+            TtyChunk::StdOut(bytes) => (0, bytes),
+            TtyChunk::StdErr(bytes) => (1, bytes),
+            TtyChunk::StdIn(_) => unreachable!(),
+        };
+        // Anything after the marker was printed after the snippet finished
+        if self.done[i] {
+            return vec![];
+        }
+        let pending = &mut self.pending[i];
+        pending.extend_from_slice(&bytes);
+        let shown = match find(pending, self.marker) {
+            Some(start) => match pending[start..].iter().position(|&b| b == b'\n') {
+                Some(len) => {
+                    let line = &pending[start + self.marker.len()..start + len];
+                    self.status = str::from_utf8(line)
+                        .ok()
+                        .and_then(|status| status.trim().parse().ok())
+                        .or(self.status);
+                    self.done[i] = true;
+                    pending.truncate(start);
+                    pending.split_off(0)
+                }
+                // Wait for the rest of the line
+                None => pending.drain(..start).collect(),
+            },
+            None => {
+                // Hold back the longest end that the marker starts with
+                let held = (1..self.marker.len().min(pending.len() + 1))
+                    .rev()
+                    .find(|&n| pending.ends_with(&self.marker[..n]))
+                    .unwrap_or(0);
+                pending.drain(..pending.len() - held).collect()
+            }
+        };
+        Self::chunks(i, shown)
+    }
+
+    /// Everything that was held back, for when the interpreter stops without finishing
+    fn finish(&mut self) -> Vec<TtyChunk> {
+        let stdout = std::mem::take(&mut self.pending[0]);
+        let stderr = std::mem::take(&mut self.pending[1]);
+        let mut chunks = Self::chunks(0, stdout);
+        chunks.extend(Self::chunks(1, stderr));
+        chunks
+    }
+
+    fn chunks(i: usize, bytes: Vec<u8>) -> Vec<TtyChunk> {
+        match (i, bytes.is_empty()) {
+            (_, true) => vec![],
+            (0, false) => vec![TtyChunk::StdOut(bytes)],
+            (_, false) => vec![TtyChunk::StdErr(bytes)],
+        }
+    }
+}
+
+/// Where `needle` first appears in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// One command to run in a container
+pub(crate) struct Phase<'a> {
+    pub cmd: Vec<&'a str>,
+    pub env: &'a [String],
+    pub stdin: Option<&'a str>,
+    pub timeout: Duration,
+    pub compiling: bool,
+}
+
+// Replace ``` with something that looks really similar
+fn escape_codeblock(code: &str) -> Cow<'_, str> {
+    static CODE_BLOCK_FENCE: Lazy<Regex> = Lazy::new(|| Regex::new(r"```").unwrap());
+    CODE_BLOCK_FENCE.replace_all(code, "\u{02CB}\u{02CB}\u{02CB}")
+}
+
+/// A piece of output, tagged with the stream it was written to. Programs can write any bytes, so
+/// it's only decoded when shown
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Chunk {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+}
+
+impl Chunk {
+    fn new(stream: StdStream, bytes: Vec<u8>) -> Self {
+        match stream {
+            StdStream::Stdout => Chunk::Stdout(bytes),
+            StdStream::Stderr => Chunk::Stderr(bytes),
+        }
+    }
+
+    fn stream(&self) -> StdStream {
+        match self {
+            Chunk::Stdout(_) => StdStream::Stdout,
+            Chunk::Stderr(_) => StdStream::Stderr,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Chunk::Stdout(bytes) | Chunk::Stderr(bytes) => bytes,
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            Chunk::Stdout(bytes) | Chunk::Stderr(bytes) => bytes,
+        }
+    }
+}
+
+/// How to show a program's stdout and stderr
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum OutputMode {
+    /// Both streams in the order they were written
+    #[default]
+    Interleaved,
+    /// Only stdout
+    Stdout,
+    /// Stdout and stderr in their own sections
+    Split,
+    /// Stdout as a hexdump, for programs that write binary data. Stderr gets its own section
+    Hex,
+}
+
+impl FromStr for OutputMode {
+    type Err = OptionsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interleaved" => Ok(OutputMode::Interleaved),
+            "stdout" => Ok(OutputMode::Stdout),
+            "split" => Ok(OutputMode::Split),
+            "hex" => Ok(OutputMode::Hex),
+            _ => Err(OptionsError::UnknownValue(s.to_owned())),
+        }
+    }
+}
+
+/// The output of one command
+#[derive(Debug, Eq, PartialEq)]
+pub struct Output {
+    pub status: u64,
+    pub termination: Termination,
+    /// In the order it was written. Adjacent chunks are always from different streams
+    pub tty: Vec<Chunk>,
+    /// Missing if it wasn't measured, like for commands that are still running
+    pub usage: Option<Usage>,
+    /// What the program left in `OUTPUT_DIR`
+    pub files: OutputFiles,
+}
+
+/// Where programs can leave files to have them sent back, relative to the working directory
+pub const OUTPUT_DIR: &str = "out";
+
+/// The files a program left in `OUTPUT_DIR`, as many as fit in the limits
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct OutputFiles {
+    pub files: Vec<OutputFile>,
+    /// Whether some were left out for going over the limits
+    pub truncated: bool,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct OutputFile {
+    /// Relative to `OUTPUT_DIR`
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+impl OutputFiles {
+    /// Reads the regular files in `archive`, a tarball of `OUTPUT_DIR`, keeping at most
+    /// `max_files` of them and `max_bytes` in total. Zero means no limit. An archive that ends
+    /// early counts as truncated
+    fn from_archive(archive: &[u8], max_files: usize, max_bytes: u64) -> Self {
+        let mut files = Self::default();
+        let mut bytes = 0;
+        let mut archive = tar::Archive::new(archive);
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(_) => {
+                files.truncated = true;
+                return files;
+            }
+        };
+        for entry in entries {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => {
+                    files.truncated = true;
+                    break;
+                }
+            };
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            let path = match entry.path() {
+                // Everything is under the directory itself
+                Ok(path) => match path.strip_prefix(OUTPUT_DIR) {
+                    Ok(path) => path.to_string_lossy().into_owned(),
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            };
+            let size = entry.size();
+            if (max_files > 0 && files.files.len() >= max_files)
+                || (max_bytes > 0 && bytes + size > max_bytes)
+            {
+                files.truncated = true;
+                continue;
+            }
+            let mut contents = Vec::new();
+            // A file cut off partway through reads fine up to where it stops
+            if entry.read_to_end(&mut contents).is_err() || contents.len() as u64 != size {
+                files.truncated = true;
+                break;
+            }
+            bytes += size;
+            files.files.push(OutputFile { path, contents });
+        }
+        files
+    }
+}
+
+/// Why a command stopped running
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Termination {
+    /// On its own, with this status
+    Exited(u64),
+    /// Killed after running for longer than this
+    TimedOut(Duration),
+    /// Killed after printing more than this many bytes
+    OutputOverflow(usize),
+    /// Killed by the kernel for using more memory than it was allowed
+    OutOfMemory,
+    /// Killed by this signal
+    Signal(u8),
+}
+
+/// How a command's process ended
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Exit {
+    /// Like shells and containers report it, 128 + N for death by signal N
+    pub status: u64,
+    /// The signal that killed it, if the backend can tell that apart from exiting with 128 + N
+    pub signal: Option<u8>,
+}
+
+impl Exit {
+    /// An exit where all we know is the status
+    pub fn status(status: u64) -> Self {
+        Self {
+            status,
+            signal: None,
+        }
+    }
+}
+
+impl Termination {
+    /// Why a command that wasn't stopped by us ended. A status of 128 + N on its own could just
+    /// as well be the program calling `exit(128 + N)`, so it's only a signal if we know it was
+    pub(crate) fn from_exit(exit: Exit) -> Self {
+        match exit.signal {
+            Some(signal) => Termination::Signal(signal),
+            None => Termination::Exited(exit.status),
+        }
+    }
+
+    /// Whether the kernel's out of memory killer could be behind this
+    pub fn maybe_out_of_memory(self) -> bool {
+        matches!(self, Termination::Exited(_) | Termination::Signal(_))
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Termination::Exited(status) => write!(f, "exited with status {}", status),
+            Termination::TimedOut(limit) => write!(f, "killed: exceeded {:?} time limit", limit),
+            Termination::OutputOverflow(max_bytes) => {
+                write!(f, "killed: exceeded {} output limit", Bytes(max_bytes))
+            }
+            Termination::OutOfMemory => write!(f, "killed: ran out of memory"),
+            Termination::Signal(signal) => match signal_name(signal) {
+                Some(name) => write!(f, "killed by {}", name),
+                None => write!(f, "killed by signal {}", signal),
+            },
+        }
+    }
+}
+
+/// The names of the standard signals on Linux
+fn signal_name(signal: u8) -> Option<&'static str> {
+    const NAMES: [&str; 31] = [
+        "SIGHUP",
+        "SIGINT",
+        "SIGQUIT",
+        "SIGILL",
+        "SIGTRAP",
+        "SIGABRT",
+        "SIGBUS",
+        "SIGFPE",
+        "SIGKILL",
+        "SIGUSR1",
+        "SIGSEGV",
+        "SIGUSR2",
+        "SIGPIPE",
+        "SIGALRM",
+        "SIGTERM",
+        "SIGSTKFLT",
+        "SIGCHLD",
+        "SIGCONT",
+        "SIGSTOP",
+        "SIGTSTP",
+        "SIGTTIN",
+        "SIGTTOU",
+        "SIGURG",
+        "SIGXCPU",
+        "SIGXFSZ",
+        "SIGVTALRM",
+        "SIGPROF",
+        "SIGWINCH",
+        "SIGIO",
+        "SIGPWR",
+        "SIGSYS",
+    ];
+    NAMES.get(usize::from(signal).checked_sub(1)?).copied()
+}
+
+/// A size in the largest unit that divides it evenly
+struct Bytes(usize);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            n if n > 0 && n % (1024 * 1024) == 0 => write!(f, "{} MiB", n / (1024 * 1024)),
+            n if n > 0 && n % 1024 == 0 => write!(f, "{} KiB", n / 1024),
+            n => write!(f, "{} bytes", n),
+        }
+    }
+}
+
+/// The resources a command used. CPU time and memory are missing where the backend can't measure
+/// them.
+///
+/// The bubblewrap backend reads them from the command's cgroup once it exits, so they're exact. The
+/// docker backend can only sample them about once a second while the command runs: they leave out
+/// its last partial second, the peak is the largest sample rather than the true peak, and commands
+/// that finish within a second usually get wall time alone
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Usage {
+    pub wall: Duration,
+    pub cpu: Option<Duration>,
+    pub peak_memory_bytes: Option<u64>,
+}
+
+impl Usage {
+    fn record(&mut self, sample: Sample) {
+        // CPU time only goes up, but a sample taken as the command exits can come back empty
+        self.cpu = self.cpu.max(Some(sample.cpu));
+        self.peak_memory_bytes = self.peak_memory_bytes.max(Some(sample.memory_bytes));
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}s wall", self.wall.as_secs_f64())?;
+        if let Some(cpu) = self.cpu {
+            write!(f, ", {:.2}s CPU", cpu.as_secs_f64())?;
+        }
+        if let Some(bytes) = self.peak_memory_bytes {
+            write!(
+                f,
+                ", {:.1} MiB peak memory",
+                bytes as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A reading of a running command's resource usage so far
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sample {
+    pub cpu: Duration,
+    pub memory_bytes: u64,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+
+    /// The status, explained if the command didn't just exit
+    fn status_text(&self) -> String {
+        match self.termination {
+            Termination::Exited(_) => self.status.to_string(),
+            reason => format!("{} ({})", self.status, reason),
+        }
+    }
+
+    /// Both streams in the order they were written, with anything that isn't UTF-8 replaced
+    pub fn text(&self) -> String {
+        let bytes: Vec<u8> = self.tty.iter().flat_map(Chunk::bytes).copied().collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    #[cfg(test)]
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.bytes(StdStream::Stdout)).into_owned()
+    }
+
+    #[cfg(test)]
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.bytes(StdStream::Stderr)).into_owned()
+    }
+
+    /// Everything written to `stream`, exactly as it was written
+    fn bytes(&self, stream: StdStream) -> Vec<u8> {
+        self.tty
+            .iter()
+            .filter(|c| c.stream() == stream)
+            .flat_map(Chunk::bytes)
+            .copied()
+            .collect()
+    }
+
+    /// Whether `mode` has to replace some of what it shows as text, so that only
+    /// `OutputMode::Hex` can show all of it
+    fn is_binary(&self, mode: OutputMode) -> bool {
+        let is_binary = |stream| str::from_utf8(&self.bytes(stream)).is_err();
+        match mode {
+            OutputMode::Interleaved | OutputMode::Split => {
+                is_binary(StdStream::Stdout) || is_binary(StdStream::Stderr)
+            }
+            OutputMode::Stdout => is_binary(StdStream::Stdout),
+            OutputMode::Hex => false,
+        }
+    }
+
+    /// What `mode` shows, as (heading, text) sections
+    fn sections(&self, mode: OutputMode) -> Vec<(Option<&'static str>, String)> {
+        self.raw_sections(mode)
+            .into_iter()
+            .map(|(heading, bytes)| (heading, String::from_utf8_lossy(&bytes).into_owned()))
+            .collect()
+    }
+
+    /// Like `sections`, but with the bytes that were written instead of what can be shown of them
+    fn raw_sections(&self, mode: OutputMode) -> Vec<(Option<&'static str>, Vec<u8>)> {
+        let split = |stdout: Vec<u8>| {
+            let sections: Vec<_> = [
+                ("STDOUT", stdout),
+                ("STDERR", self.bytes(StdStream::Stderr)),
+            ]
+            .into_iter()
+            .filter(|(_, bytes)| !bytes.is_empty())
+            .map(|(heading, bytes)| (Some(heading), bytes))
+            .collect();
+            if sections.is_empty() {
+                vec![(None, Vec::new())]
+            } else {
+                sections
+            }
+        };
+        match mode {
+            OutputMode::Interleaved => vec![(
+                None,
+                self.tty.iter().flat_map(Chunk::bytes).copied().collect(),
+            )],
+            OutputMode::Stdout => vec![(None, self.bytes(StdStream::Stdout))],
+            OutputMode::Split => split(self.bytes(StdStream::Stdout)),
+            OutputMode::Hex => split(hexdump(&self.bytes(StdStream::Stdout)).into_bytes()),
+        }
+    }
+
+    /// How many codepoints `mode` would show if there was no limit
+    fn len(&self, mode: OutputMode) -> usize {
+        self.sections(mode)
+            .iter()
+            .map(|(_, text)| text.chars().count())
+            .sum()
+    }
+
+    fn fmt_mode(
+        &self,
+        f: &mut impl fmt::Write,
+        mode: OutputMode,
+        max_codepoints: usize,
+    ) -> fmt::Result {
+        if !self.success() {
+            writeln!(f, "**EXIT STATUS:** {}", self.status_text())?;
+        }
+
+        let sections = self.sections(mode);
+        let lens: Vec<_> = sections
+            .iter()
+            .map(|(_, text)| text.chars().count())
+            .collect();
+        let budgets = share_budget(&lens, max_codepoints);
+        for (i, ((heading, text), budget)) in sections.iter().zip(budgets).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if let Some(heading) = heading {
+                writeln!(f, "**{}:**", heading)?;
+            }
+            write!(f, "```\n{}```", escape_codeblock(&preview(text, budget)))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_mode(f, OutputMode::default(), MAX_OUTPUT_CODEPOINTS)
+    }
+}
+
+/// Everything that happened while running some code
+#[derive(Debug, Eq, PartialEq)]
+pub struct Report {
+    /// Only present for languages with a compile step
+    pub compile: Option<Output>,
+    /// Missing if compilation failed
+    pub run: Option<Output>,
+}
+
+impl Report {
+    /// Shows the program's output according to `mode`. Compiler output is always interleaved
+    pub fn render(&self, mode: OutputMode) -> Render<'_> {
+        Render { report: self, mode }
+    }
+
+    /// The full output if `render` can't fit all of it in a message. Sections that aren't UTF-8
+    /// are hexdumped, so that it's always text and nothing is lost
+    pub fn attachment(&self, mode: OutputMode) -> Option<Vec<u8>> {
+        let compile = self.shown_compile();
+        let compile_len = compile.map_or(0, |c| c.len(OutputMode::Interleaved));
+        let run_budget = MAX_OUTPUT_CODEPOINTS - compile_len.min(MAX_COMPILE_CODEPOINTS);
+        let truncated = compile_len > MAX_COMPILE_CODEPOINTS
+            || self
+                .run
+                .as_ref()
+                .is_some_and(|run| run.len(mode) > run_budget);
+        if !truncated {
+            return None;
+        }
+
+        let mut parts = Vec::new();
+        if let Some(compile) = compile.filter(|c| !c.tty.is_empty()) {
+            for (_, bytes) in compile.raw_sections(OutputMode::Interleaved) {
+                parts.push(("COMPILER OUTPUT", bytes));
+            }
+        }
+        if let Some(run) = &self.run {
+            for (heading, bytes) in run.raw_sections(mode) {
+                parts.push((heading.unwrap_or("PROGRAM OUTPUT"), bytes));
+            }
+        }
+        for (_, bytes) in &mut parts {
+            if str::from_utf8(bytes).is_err() {
+                *bytes = hexdump(bytes).into_bytes();
+            }
+        }
+        if let [(_, bytes)] = parts.as_slice() {
+            return Some(bytes.clone());
+        }
+        let mut attachment = Vec::new();
+        for (heading, bytes) in parts {
+            attachment.extend_from_slice(format!("==> {} <==\n", heading).as_bytes());
+            attachment.extend_from_slice(&bytes);
+            if !attachment.ends_with(b"\n") {
+                attachment.push(b'\n');
+            }
+        }
+        Some(attachment)
+    }
+
+    /// What the program left in `OUTPUT_DIR`
+    pub fn files(&self) -> &OutputFiles {
+        static NONE: OutputFiles = OutputFiles {
+            files: Vec::new(),
+            truncated: false,
+        };
+        self.run.as_ref().map_or(&NONE, |run| &run.files)
+    }
+
+    /// Compiler output is only worth a section if there's something to show
+    fn shown_compile(&self) -> Option<&Output> {
+        self.compile
+            .as_ref()
+            .filter(|c| !c.success() || !c.tty.is_empty())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.render(OutputMode::default()).fmt(f)
+    }
+}
+
+pub struct Render<'a> {
+    report: &'a Report,
+    mode: OutputMode,
+}
+
+impl fmt::Display for Render<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let compile = self.report.shown_compile();
+        let mut max_codepoints = MAX_OUTPUT_CODEPOINTS;
+        if let Some(compile) = compile {
+            if !compile.tty.is_empty() {
+                let text = compile.text();
+                let text = preview(&text, MAX_COMPILE_CODEPOINTS);
+                max_codepoints -= text.chars().count();
+                writeln!(
+                    f,
+                    "**COMPILER OUTPUT:**\n```\n{}```",
+                    escape_codeblock(&text)
+                )?;
+            }
+            if !compile.success() {
+                write!(f, "**COMPILE STATUS:** {}", compile.status_text())?;
+            }
+        }
+        if let Some(run) = &self.report.run {
+            if compile.is_some() {
+                writeln!(f, "**PROGRAM OUTPUT:**")?;
+            }
+            run.fmt_mode(f, self.mode, max_codepoints)?;
+        }
+        let last = self.report.run.as_ref().or(self.report.compile.as_ref());
+        if let Some(usage) = last.and_then(|output| output.usage) {
+            write!(f, "\n*{}*", usage)?;
+        }
+        if let Some(run) = &self.report.run {
+            if run.is_binary(self.mode) {
+                write!(f, "{}", BINARY_OUTPUT)?;
+            }
+        }
+        if self.report.files().truncated {
+            write!(f, "{}", FILES_TRUNCATED)?;
+        }
+        Ok(())
+    }
+}
+
+/// A look at a command that's still running
+#[derive(Debug)]
+pub struct Progress {
+    pub compiling: bool,
+    pub tty: Vec<Chunk>,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn render(&self, mode: OutputMode) -> String {
+        let output = Output {
+            status: 0,
+            termination: Termination::Exited(0),
+            tty: self.tty.clone(),
+            usage: None,
+            files: Default::default(),
+        };
+        let mut rendered = String::new();
+        let (mode, verb) = if self.compiling {
+            rendered.push_str("**COMPILER OUTPUT:**\n");
+            (OutputMode::Interleaved, "compiling")
+        } else {
+            (mode, "running")
+        };
+        let max_codepoints = MAX_OUTPUT_CODEPOINTS - PROGRESS_FOOTER_CODEPOINTS;
+        // Writing to a String can't fail
+        output
+            .fmt_mode(&mut rendered, mode, max_codepoints)
+            .unwrap();
+        rendered.push_str(&format!("\n*{}… ({}s)*", verb, self.elapsed.as_secs()));
+        rendered
+    }
+}
+
+const PROGRESS_FOOTER_CODEPOINTS: usize =
+    "**COMPILER OUTPUT:**\n\n*compiling… (18446744073709551615s)*".len();
+
+/// How often to take a look at running commands
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `bytes` laid out like `hexdump -C`, sixteen to a line
+fn hexdump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        dump.push_str(&format!("{:08x} ", i * 16));
+        for j in 0..16 {
+            if j % 8 == 0 {
+                dump.push(' ');
+            }
+            match line.get(j) {
+                Some(byte) => dump.push_str(&format!("{:02x} ", byte)),
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str(" |");
+        dump.extend(line.iter().map(|&byte| match byte {
+            b' '..=b'~' => byte as char,
+            _ => '.',
+        }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+/// Shortens `text` to at most `max_codepoints` by cutting out the middle
+fn preview(text: &str, max_codepoints: usize) -> Cow<'_, str> {
+    let len = text.chars().count();
+    if len <= max_codepoints {
+        return Cow::Borrowed(text);
+    }
+    // Enough room for the note about what we cut
+    let keep = max_codepoints.saturating_sub(PREVIEW_NOTE_CODEPOINTS);
+    let head = keep / 2;
+    let tail = keep - head;
+    let byte_index = |n| text.char_indices().nth(n).map_or(text.len(), |(i, _)| i);
+    Cow::Owned(format!(
+        "{}\n[... {} characters omitted ...]\n{}",
+        &text[..byte_index(head)],
+        len - keep,
+        &text[byte_index(len - tail)..],
+    ))
+}
+
+const PREVIEW_NOTE_CODEPOINTS: usize =
+    "\n[... 18446744073709551615 characters omitted ...]\n".len();
+
+/// Splits `budget` between texts with the given lengths. Short texts get everything they need and
+/// long texts split what's left evenly
+fn share_budget(lens: &[usize], mut budget: usize) -> Vec<usize> {
+    let mut order: Vec<_> = (0..lens.len()).collect();
+    order.sort_by_key(|&i| lens[i]);
+    let mut shares = vec![0; lens.len()];
+    for (n, &i) in order.iter().enumerate() {
+        shares[i] = lens[i].min(budget / (lens.len() - n));
+        budget -= shares[i];
+    }
+    shares
+}
+
+struct OutputBuilder<S>
+where
+    S: Stream<Item = shiplift::Result<TtyChunk>> + Unpin,
+{
+    buf: Vec<Chunk>,
+    /// The start of a character each stream is partway through writing, held back until the rest
+    /// of it arrives so that chunks never split one
+    partial: [Vec<u8>; 2],
+    bytes: usize,
+    max_bytes: usize,
+    logs: Option<S>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum StdStream {
+    Stdout,
+    Stderr,
+}
+
+const MAX_OUTPUT_CODEPOINTS: usize = serenity::constants::MESSAGE_CODE_LIMIT
+    - "mentions_cost_22_chars: **EXIT STATUS:** 255 (killed: exceeded 99999s time limit)\n**STDOUT:**\n```...```\n**STDERR:**\n```\n```"
+        .len()
+    - USAGE_FOOTER_CODEPOINTS
+    - BINARY_OUTPUT.len()
+    - FILES_TRUNCATED.len();
+
+const BINARY_OUTPUT: &str =
+    "\n*Some of the output isn't text, so it's shown as �. Add `output=hex` to see its bytes.*";
+
+const FILES_TRUNCATED: &str = "\n*Some files were left out for being too big or too many.*";
+
+const USAGE_FOOTER_CODEPOINTS: usize =
+    "\n*99999.99s wall, 99999.99s CPU, 999999.9 MiB peak memory*".len();
+
+// The compiler gets at most half of the message. The rest goes to the program
+const MAX_COMPILE_CODEPOINTS: usize = MAX_OUTPUT_CODEPOINTS / 2
+    - "**COMPILER OUTPUT:**\n```\n...```\n**COMPILE STATUS:** 255 (killed: exceeded 99999s time limit)**PROGRAM OUTPUT:**\n"
+        .len();
+
+impl<S> OutputBuilder<S>
+where
+    S: Stream<Item = shiplift::Result<TtyChunk>> + Unpin,
+{
+    fn new(logs: S, max_bytes: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            partial: Default::default(),
+            bytes: 0,
+            max_bytes,
+            logs: Some(logs),
+        }
+    }
+
+    /// What we've received so far, apart from any characters that are still being written
+    fn snapshot(&self) -> Vec<Chunk> {
+        self.buf.clone()
+    }
+
+    fn build(mut self) -> Vec<Chunk> {
+        // Whatever never got finished is kept as it is
+        for stream in [StdStream::Stdout, StdStream::Stderr] {
+            let partial = std::mem::take(&mut self.partial[stream as usize]);
+            Self::merge(&mut self.buf, stream, &partial);
+        }
+        self.buf
+    }
+
+    fn push(buf: &mut Vec<Chunk>, partial: &mut [Vec<u8>; 2], stream: StdStream, bytes: &[u8]) {
+        let partial = &mut partial[stream as usize];
+        partial.extend_from_slice(bytes);
+        let rest = partial.split_off(complete_len(partial));
+        let complete = std::mem::replace(partial, rest);
+        Self::merge(buf, stream, &complete);
+    }
+
+    fn merge(buf: &mut Vec<Chunk>, stream: StdStream, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        // Merge writes to the same stream so that chunks don't depend on how the program buffers
+        match buf.last_mut() {
+            Some(last) if last.stream() == stream => last.bytes_mut().extend_from_slice(bytes),
+            _ => buf.push(Chunk::new(stream, bytes.to_vec())),
+        }
+    }
+
+    /// Reads output until there isn't any more or there's too much. Output that's already been
+    /// cut off stays that way
+    async fn extend(&mut self) -> Result<Extended, RunError> {
+        let logs = match self.logs.as_mut() {
+            Some(logs) => logs,
+            None => return Ok(Extended::Finished),
+        };
+
+        // TODO: Sometimes logs.next.await() == None even though not all the logs have been
+        // returned... I think docker is closing our connection incorrectly? See `test_no_newline`
+        while let Some(chunk) = logs.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    self.logs = None;
+                    return Err(RunError::daemon(err));
+                }
+            };
+            let (stream, bytes) = match chunk {
+                TtyChunk::StdOut(bytes) => (StdStream::Stdout, bytes),
+                TtyChunk::StdErr(bytes) => (StdStream::Stderr, bytes),
+                TtyChunk::StdIn(_) => {
+                    self.logs = None;
+                    return Err(RunError::daemon(
+                        "got stdin back from an attached container",
+                    ));
+                }
+            };
+            self.bytes += bytes.len();
+            if self.bytes > self.max_bytes {
+                // A character cut off here would only get in the way of the note
+                self.partial = Default::default();
+                Self::push(&mut self.buf, &mut self.partial, stream, b"...");
+                self.logs = None;
+                return Ok(Extended::Overflowed);
+            }
+            Self::push(&mut self.buf, &mut self.partial, stream, &bytes);
+        }
+        self.logs = None;
+        Ok(Extended::Finished)
+    }
+}
+
+/// Why `OutputBuilder::extend` stopped reading
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Extended {
+    /// The command closed its output
+    Finished,
+    /// It printed more than we keep
+    Overflowed,
+}
+
+/// How much of `bytes` can be decoded without waiting for more: all of it, unless it ends partway
+/// through a character. Bytes that aren't UTF-8 at all don't need to wait
+fn complete_len(bytes: &[u8]) -> usize {
+    let mut start = 0;
+    loop {
+        match str::from_utf8(&bytes[start..]) {
+            Ok(_) => return bytes.len(),
+            Err(err) => match err.error_len() {
+                Some(len) => start += err.valid_up_to() + len,
+                None => return start + err.valid_up_to(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+static TEST_RUNNER: once_cell::sync::Lazy<DockerRunner> =
+    once_cell::sync::Lazy::new(|| DockerRunner {
+        docker: Docker::new(),
+        api: DockerApi::new().unwrap(),
+        timeout: Duration::from_secs(10),
+        compile_timeout: Duration::from_secs(30),
+        // As much as needed
+        cpus: 0.0,
+        memory_bytes: 0,
+        max_output_bytes: 64 * 1024,
+        workdir_bytes: 128 * 1024 * 1024,
+        read_only_rootfs: true,
+        limits: ProcessLimits::default(),
+        hardening: Hardening {
+            seccomp: true,
+            no_new_privileges: true,
+            ..Default::default()
+        },
+        max_files: 8,
+        max_files_bytes: 1024 * 1024,
+        pool: Default::default(),
+        sessions: Default::default(),
+        builds: Default::default(),
+        run_ids: Default::default(),
+        holder_image: DEFAULT_HOLDER_IMAGE.to_owned(),
+    });
+
+#[cfg(test)]
+pub(crate) async fn test_run(lang: LangRef, code: &str) -> Result<Report, RunError> {
+    test_run_stdin(lang, code, None).await
+}
+
+#[cfg(test)]
+pub(crate) async fn test_run_stdin(
+    lang: LangRef,
+    code: &str,
+    stdin: Option<&str>,
+) -> Result<Report, RunError> {
+    test_run_files(lang, code, &[], stdin).await
+}
+
+/// Runs `code` as the language's entry file alongside `extra_files`
+#[cfg(test)]
+pub(crate) async fn test_run_files(
+    lang: LangRef,
+    code: &str,
+    extra_files: &[SourceFile<'_>],
+    stdin: Option<&str>,
+) -> Result<Report, RunError> {
+    test_run_with(lang, code, extra_files, stdin, &RunOptions::default(), None).await
+}
+
+/// Like `test_run_measured`, but without the resource usage so that outputs can be compared
+#[cfg(test)]
+pub(crate) async fn test_run_with(
+    lang: LangRef,
+    code: &str,
+    extra_files: &[SourceFile<'_>],
+    stdin: Option<&str>,
+    opts: &RunOptions,
+    progress: Option<&Sender<Progress>>,
+) -> Result<Report, RunError> {
+    let mut report = test_run_measured(lang, code, extra_files, stdin, opts, progress).await?;
+    for output in [&mut report.compile, &mut report.run].into_iter().flatten() {
+        output.usage = None;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+pub(crate) async fn test_run_measured(
+    lang: LangRef,
+    code: &str,
+    extra_files: &[SourceFile<'_>],
+    stdin: Option<&str>,
+    opts: &RunOptions,
+    progress: Option<&Sender<Progress>>,
+) -> Result<Report, RunError> {
+    let spec = lang.run_spec(Default::default()).unwrap();
+    let mut files = vec![SourceFile {
+        path: spec.code_path,
+        contents: code,
+    }];
+    files.extend(extra_files.iter().map(|f| SourceFile {
+        path: f.path,
+        contents: f.contents,
+    }));
+    match TEST_RUNNER
+        .run_code(&spec, &files, stdin, opts, progress)
+        .await
+    {
+        Err(RunError::ImageMissing) => {
+            TEST_RUNNER.build(&spec).await.unwrap();
+            TEST_RUNNER
+                .run_code(&spec, &files, stdin, opts, progress)
+                .await
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::*;
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let output = test_run(&Python, "while True: pass").await.unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                // The status Python returns from SIGKILL
+                status: 137,
+                termination: Termination::TimedOut(Duration::from_secs(10)),
+                tty: vec![],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
     async fn test_output() {
         let code = r#"
 import sys
@@ -344,48 +2523,1292 @@ sys.exit(123)
 "#;
         let output = test_run(&Python, code).await.unwrap();
         assert_eq!(
-            output,
-            Output {
-                status: 123,
-                tty: "stdout\nstderr\n".into(),
-            }
+            output.run,
+            Some(Output {
+                status: 123,
+                termination: Termination::Exited(123),
+                tty: vec![
+                    Chunk::Stdout("stdout\n".into()),
+                    Chunk::Stderr("stderr\n".into()),
+                ],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stdin() {
+        let code = r#"
+import sys
+for line in sys.stdin:
+    print(line.strip()[::-1])
+"#;
+        let output = test_run_stdin(&Python, code, Some("hello\nworld\n"))
+            .await
+            .unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("olleh\ndlrow\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stdin_too_big() {
+        // Turned away before anything is created, like code that doesn't fit
+        let stdin = "x".repeat(TEST_RUNNER.workdir_bytes as usize);
+        match test_run_stdin(&Python, "pass", Some(&stdin)).await {
+            Err(RunError::TooBig(bytes)) => assert_eq!(bytes, TEST_RUNNER.workdir_bytes + 4),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_stdin() {
+        // Without a stdin block the program should see EOF rather than hang
+        let code = r#"
+import sys
+print(len(sys.stdin.read()))
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("0\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multiple_files() {
+        let files = [
+            SourceFile {
+                path: "greeting.py",
+                contents: "GREETING = 'Hello, World!'\n",
+            },
+            SourceFile {
+                path: "pkg/__init__.py",
+                contents: "from greeting import GREETING\n",
+            },
+        ];
+        let output = test_run_files(&Python, "import pkg\nprint(pkg.GREETING)\n", &files, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("Hello, World!\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_c_header() {
+        let files = [
+            SourceFile {
+                path: "util.h",
+                contents: "int add(int a, int b);\n",
+            },
+            SourceFile {
+                path: "util.c",
+                contents: "int add(int a, int b) { return a + b; }\n",
+            },
+        ];
+        let code = r#"
+#include <stdio.h>
+#include "util.h"
+int main() {
+    printf("%d\n", add(1, 2));
+    return 0;
+}"#;
+        let output = test_run_files(&C, code, &files, None).await.unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("3\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fortran_module() {
+        let files = [SourceFile {
+            path: "util.f95",
+            contents: r#"
+module util
+contains
+    integer function add(a, b)
+        integer, intent(in) :: a, b
+        add = a + b
+    end function add
+end module util
+"#,
+        }];
+        let code = r#"
+program main
+    use util
+    print '(i0)', add(1, 2)
+end program main
+"#;
+        let output = test_run_files(&Fortran, code, &files, None).await.unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("3\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_args_env() {
+        let code = r#"
+import os, sys
+print(sys.argv[1:])
+print(os.environ["GREETING"])
+"#;
+        let opts = RunOptions {
+            args: vec!["-v".into(), "two words".into()],
+            env: vec![("GREETING".into(), "Hello, World!".into())],
+            ..RunOptions::default()
+        };
+        let output = test_run_with(&Python, code, &[], None, &opts, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("['-v', 'two words']\nHello, World!\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_args_sh_c() {
+        // Languages that compile first run through `sh -c` and must still forward arguments
+        let code = r#"
+#include <stdio.h>
+int main(int argc, char **argv) {
+    for (int i = 1; i < argc; i++) printf("%s\n", argv[i]);
+    return 0;
+}"#;
+        let opts = RunOptions {
+            args: vec!["a".into(), "b c".into()],
+            ..RunOptions::default()
+        };
+        let output = test_run_with(&C, code, &[], None, &opts, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("a\nb c\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    // TODO: This test is flaky...
+    #[tokio::test]
+    async fn test_ordering() {
+        // This code causes issues with python buffering sometimes
+        let code = r#"
+import os
+print(0)
+os.system("echo 1")
+print(2)
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("0\n1\n2\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    // TODO: This test is flaky...
+    #[tokio::test]
+    async fn test_no_newline() {
+        let code = r#"
+import sys
+sys.stdout.write("x" * 1000)
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("x".repeat(1000).into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_binary_output() {
+        let code = r#"
+import sys
+sys.stdout.buffer.write(bytes(range(256)))
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        let run = output.run.unwrap();
+        assert_eq!(run.tty, [Chunk::Stdout((0..=255).collect())]);
+        assert!(run.is_binary(OutputMode::Interleaved));
+    }
+
+    #[tokio::test]
+    async fn test_long_output() {
+        // Longer than a message but shorter than the capture limit
+        let code = r#"
+for i in range(10000):
+    print(i)
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        let run = output.run.as_ref().unwrap();
+        assert_eq!(run.status, 0);
+        assert!(run.text().ends_with("9998\n9999\n"), "{}", run.text());
+        let attachment = output.attachment(OutputMode::default()).unwrap();
+        assert_eq!(attachment, run.text().into_bytes());
+        let message = output.to_string();
+        assert!(message.contains("characters omitted"), "{}", message);
+        assert!(message.ends_with("9999\n```"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_output_files() {
+        let code = r#"
+import os
+with open("out/hello.txt", "w") as f:
+    f.write("Hello, World!")
+os.mkdir("out/sub")
+with open("out/sub/bytes", "wb") as f:
+    f.write(bytes([0, 255]))
+with open("out/big", "wb") as f:
+    f.write(bytes(2 * 1024 * 1024))
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        let mut files = output.run.unwrap().files;
+        files.files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(
+            files,
+            OutputFiles {
+                files: vec![
+                    OutputFile {
+                        path: "hello.txt".to_owned(),
+                        contents: b"Hello, World!".to_vec(),
+                    },
+                    OutputFile {
+                        path: "sub/bytes".to_owned(),
+                        contents: vec![0, 255],
+                    },
+                ],
+                truncated: true,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disk_quota() {
+        let code = r#"
+import os
+chunk = b"x" * 1024 * 1024
+try:
+    with open("fill", "wb", buffering=0) as f:
+        while True:
+            f.write(chunk)
+except OSError as err:
+    print(err.strerror)
+os.remove("fill")
+try:
+    open("/var/tmp/file", "w")
+except OSError as err:
+    print(err.strerror)
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout(
+                    "No space left on device\nRead-only file system\n".into()
+                )],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fork_bomb() {
+        let output = test_run(&Sh, "f() { f | f & }; f; sleep 2").await.unwrap();
+        assert!(output.run.is_some());
+        // Whatever the bomb did, it's gone and the next run works
+        let output = test_run(&Sh, "echo ok").await.unwrap();
+        assert_eq!(output.run.unwrap().stdout(), "ok\n");
+    }
+
+    #[tokio::test]
+    async fn test_huge_file() {
+        let code = r#"
+import os
+chunk = b"x" * 1024 * 1024
+try:
+    with open("big", "wb", buffering=0) as f:
+        while True:
+            f.write(chunk)
+except OSError as err:
+    print(err.strerror)
+print(os.path.getsize("big"))
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        assert_eq!(
+            output.run.unwrap().stdout(),
+            format!("File too large\n{}\n", ProcessLimits::default().fsize)
         );
     }
 
-    // TODO: This test is flaky...
     #[tokio::test]
-    async fn test_ordering() {
-        // This code causes issues with python buffering sometimes
+    async fn test_prepare() {
+        TEST_RUNNER.prepare().await.unwrap();
+        let holder = TEST_RUNNER.workdir_holder();
+        assert!(TEST_RUNNER.has_image(&holder.image()).await.unwrap());
+
+        // Another base is another image, so switching doesn't reuse the old one
+        assert_ne!(workdir_holder("busybox").image(), holder.image());
+    }
+
+    #[tokio::test]
+    async fn test_hardening() {
         let code = r#"
-import os
-print(0)
-os.system("echo 1")
-print(2)
+import ctypes
+libc = ctypes.CDLL(None, use_errno=True)
+print(libc.ptrace(0, 0, None, None), ctypes.get_errno())
+with open("/proc/self/status") as f:
+    print(*(line for line in f if line.startswith("NoNewPrivs")), end="")
 "#;
         let output = test_run(&Python, code).await.unwrap();
-        assert_eq!(
-            output,
-            Output {
-                status: 0,
-                tty: "0\n1\n2\n".into(),
+        // The default profile would let the program trace itself
+        assert_eq!(output.run.unwrap().stdout(), "-1 1\nNoNewPrivs:\t1\n");
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let spec = Python.run_spec(Default::default()).unwrap();
+        let cmd = Python.session_cmd().unwrap();
+        let id = match TEST_RUNNER.start_session(&spec, &cmd).await {
+            Err(RunError::ImageMissing) => {
+                TEST_RUNNER.build(&spec).await.unwrap();
+                TEST_RUNNER.start_session(&spec, &cmd).await.unwrap()
             }
+            result => result.unwrap(),
+        };
+        let run = |code, stdin| TEST_RUNNER.run_in_session(&id, code, stdin, None);
+
+        let first = run("x = input()\nprint(x, end='')", Some("1\n"))
+            .await
+            .unwrap();
+        assert_eq!(first.output.tty, [Chunk::Stdout("1".into())]);
+        assert!(!first.ended);
+        let second = run("import sys\nprint(x * 2)\nsys.exit(3)", None)
+            .await
+            .unwrap();
+        assert_eq!(second.output.tty, [Chunk::Stdout("11\n".into())]);
+        assert_eq!(second.output.termination, Termination::Exited(3));
+        assert!(!second.ended);
+        let error = run("y", None).await.unwrap();
+        assert_eq!(
+            error.output.stderr(),
+            "Traceback (most recent call last):\n  File \"run.py\", line 1, in <module>\nNameError: name 'y' is not defined\n",
         );
+
+        let timeout = run("while True: pass", None).await.unwrap();
+        assert_eq!(
+            timeout.output.termination,
+            Termination::TimedOut(Duration::from_secs(10))
+        );
+        assert!(timeout.ended);
+        assert!(matches!(
+            run("print(x)", None).await,
+            Err(RunError::SessionEnded)
+        ));
     }
 
-    // TODO: This test is flaky...
     #[tokio::test]
-    async fn test_no_newline() {
+    async fn test_output_overflow() {
         let code = r#"
-import sys
-sys.stdout.write("x" * 1000)
+while True:
+    print("y" * 1000)
+"#;
+        let output = test_run(&Python, code).await.unwrap();
+        let run = output.run.unwrap();
+        assert_eq!(run.status, 137);
+        assert_eq!(run.termination, Termination::OutputOverflow(64 * 1024));
+        assert!(run.text().len() <= 64 * 1024 + "...".len());
+        assert!(run.text().ends_with("..."));
+    }
+
+    #[tokio::test]
+    async fn test_signal() {
+        let code = r#"
+import os, signal
+os.kill(os.getpid(), signal.SIGSEGV)
 "#;
         let output = test_run(&Python, code).await.unwrap();
+        let run = output.run.unwrap();
+        // Docker can't tell this apart from `sys.exit(139)`, so it doesn't guess
+        assert_eq!(run.status, 139);
+        assert_eq!(run.termination, Termination::Exited(139));
+        assert_eq!(run.to_string(), "**EXIT STATUS:** 139\n```\n```");
+    }
+
+    #[tokio::test]
+    async fn test_usage() {
+        // Sleeps so that stats are sampled at least once after the allocation
+        let code = r#"
+import time
+start = time.process_time()
+while time.process_time() - start < 0.5:
+    pass
+data = bytearray(64 * 1024 * 1024)
+time.sleep(2)
+"#;
+        let output = test_run_measured(&Python, code, &[], None, &RunOptions::default(), None)
+            .await
+            .unwrap();
+        let usage = output.run.unwrap().usage.unwrap();
+        assert!(usage.wall >= Duration::from_millis(2500), "{:?}", usage);
+        assert!(
+            usage.cpu.unwrap() >= Duration::from_millis(500),
+            "{:?}",
+            usage
+        );
+        assert!(
+            usage.peak_memory_bytes.unwrap() >= 64 * 1024 * 1024,
+            "{:?}",
+            usage
+        );
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let code = r#"
+import time
+print("first", flush=True)
+time.sleep(3)
+print("second")
+"#;
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let output = test_run_with(&Python, code, &[], None, &RunOptions::default(), Some(&tx))
+            .await
+            .unwrap();
+        drop(tx);
+        assert_eq!(
+            output.run.unwrap().tty,
+            vec![Chunk::Stdout("first\nsecond\n".into())]
+        );
+        let mut snapshots = Vec::new();
+        while let Some(progress) = rx.recv().await {
+            snapshots.push(progress);
+        }
+        assert!(
+            snapshots.iter().any(|p| !p.compiling
+                && p.tty == vec![Chunk::Stdout("first\n".into())]
+                && p.elapsed >= Duration::from_secs(1)),
+            "{:?}",
+            snapshots
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compile_error() {
+        let output = test_run(&C, "int main() { return 0 }").await.unwrap();
+        let compile = output.compile.unwrap();
+        assert_eq!(compile.status, 1);
+        assert!(compile.text().contains("error"), "{}", compile.text());
+        assert_eq!(output.run, None);
+    }
+
+    #[tokio::test]
+    async fn test_compile_warning() {
+        let code = r#"
+#include <stdio.h>
+int main() {
+    int unused;
+    printf("Hello, World!\n");
+    return 0;
+}"#;
+        let output = test_run(&C, code).await.unwrap();
+        let compile = output.compile.unwrap();
+        assert_eq!(compile.status, 0);
+        assert!(compile.text().contains("unused"), "{}", compile.text());
+        assert_eq!(
+            output.run,
+            Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("Hello, World!\n".into())],
+                usage: None,
+                files: Default::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_display_report() {
+        let run = || Output {
+            status: 0,
+            termination: Termination::Exited(0),
+            tty: vec![Chunk::Stdout("Hello, World!\n".into())],
+            usage: None,
+            files: Default::default(),
+        };
+        let report = Report {
+            compile: None,
+            run: Some(run()),
+        };
+        assert_eq!(report.to_string(), "```\nHello, World!\n```");
+
+        // Quiet successful compiles don't get a section
+        let report = Report {
+            compile: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![],
+                usage: None,
+                files: Default::default(),
+            }),
+            run: Some(run()),
+        };
+        assert_eq!(report.to_string(), "```\nHello, World!\n```");
+
+        let report = Report {
+            compile: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stderr("warning: unused\n".into())],
+                usage: None,
+                files: Default::default(),
+            }),
+            run: Some(run()),
+        };
+        assert_eq!(
+            report.to_string(),
+            "**COMPILER OUTPUT:**\n```\nwarning: unused\n```\n**PROGRAM OUTPUT:**\n```\nHello, World!\n```"
+        );
+
+        let report = Report {
+            compile: Some(Output {
+                status: 1,
+                termination: Termination::Exited(1),
+                tty: vec![Chunk::Stderr("error: oops\n".into())],
+                usage: None,
+                files: Default::default(),
+            }),
+            run: None,
+        };
+        assert_eq!(
+            report.to_string(),
+            "**COMPILER OUTPUT:**\n```\nerror: oops\n```\n**COMPILE STATUS:** 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_usage_unsampled() {
+        // Like a command that exits before the first sample comes in
+        let wall = Duration::from_millis(250);
+        let run = async {
+            Ok(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![],
+                usage: Some(Usage {
+                    wall,
+                    ..Default::default()
+                }),
+                files: Default::default(),
+            })
+        };
+        let output = with_usage(run, stream::pending()).await.unwrap();
+        let usage = output.usage.unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                wall,
+                cpu: None,
+                peak_memory_bytes: None,
+            }
+        );
+        assert_eq!(usage.to_string(), "0.25s wall");
+    }
+
+    #[test]
+    fn test_display_usage() {
+        let usage = Usage {
+            wall: Duration::from_millis(1234),
+            cpu: Some(Duration::from_millis(500)),
+            peak_memory_bytes: Some(12 * 1024 * 1024 + 512 * 1024),
+        };
+        let report = Report {
+            compile: None,
+            run: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("hi\n".into())],
+                usage: Some(usage),
+                files: Default::default(),
+            }),
+        };
+        assert_eq!(
+            report.to_string(),
+            "```\nhi\n```\n*1.23s wall, 0.50s CPU, 12.5 MiB peak memory*"
+        );
+
+        // The footer is for whichever phase ran last, showing only what was measured
+        let report = Report {
+            compile: Some(Output {
+                status: 1,
+                termination: Termination::Exited(1),
+                tty: vec![],
+                usage: Some(Usage {
+                    wall: Duration::from_secs(2),
+                    ..Default::default()
+                }),
+                files: Default::default(),
+            }),
+            run: None,
+        };
+        assert_eq!(report.to_string(), "**COMPILE STATUS:** 1\n*2.00s wall*");
+    }
+
+    #[test]
+    fn test_security_opts() {
+        assert!(Hardening::default().security_opts().is_empty());
+        let opts = Hardening {
+            seccomp: true,
+            no_new_privileges: true,
+            apparmor: Some("codie".to_owned()),
+            selinux_label: Some("type:container_t".to_owned()),
+        }
+        .security_opts();
+        assert!(opts[0].starts_with("seccomp={"), "{}", opts[0]);
+        assert_eq!(
+            opts[1..],
+            [
+                "no-new-privileges:true",
+                "apparmor=codie",
+                "label=type:container_t"
+            ]
+        );
+
+        let profile: serde_json::Value =
+            serde_json::from_str(opts[0].strip_prefix("seccomp=").unwrap()).unwrap();
+        let allowed: Vec<&str> = profile["syscalls"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|rule| rule["action"] == "SCMP_ACT_ALLOW")
+            .flat_map(|rule| rule["names"].as_array().unwrap())
+            .map(|name| name.as_str().unwrap())
+            .collect();
+        for name in ["read", "write", "execve", "clone", "futex"] {
+            assert!(allowed.contains(&name), "{} isn't allowed", name);
+        }
+        for name in [
+            "ptrace",
+            "process_vm_readv",
+            "keyctl",
+            "io_uring_setup",
+            "unshare",
+        ] {
+            assert!(!allowed.contains(&name), "{} is allowed", name);
+        }
+    }
+
+    #[test]
+    fn test_session_request() {
+        assert_eq!(
+            session_request("print(1)", "é\n"),
+            b"8 3\nprint(1)\xc3\xa9\n"
+        );
+    }
+
+    /// `chunks` as (stream, text) for comparing
+    fn texts(chunks: Vec<TtyChunk>) -> Vec<(&'static str, String)> {
+        chunks
+            .into_iter()
+            .map(|chunk| match chunk {
+                TtyChunk::StdOut(bytes) => ("out", String::from_utf8(bytes).unwrap()),
+                TtyChunk::StdErr(bytes) => ("err", String::from_utf8(bytes).unwrap()),
+                TtyChunk::StdIn(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_snippet_output() {
+        let mut output = SnippetOutput::new(b"MARK");
+        let mut out = |chunk| texts(output.push(chunk));
+        assert_eq!(
+            out(TtyChunk::StdOut(b"hi\nMA".to_vec())),
+            [("out", "hi\n".to_owned())]
+        );
+        // Held back until it's clear that it isn't the marker
+        assert_eq!(
+            out(TtyChunk::StdOut(b"Y".to_vec())),
+            [("out", "MAY".to_owned())]
+        );
+        assert_eq!(
+            out(TtyChunk::StdOut(b"no newline".to_vec())),
+            [("out", "no newline".to_owned())]
+        );
+        assert_eq!(out(TtyChunk::StdOut(b"MARK 4".to_vec())), []);
+        assert_eq!(out(TtyChunk::StdOut(b"2\n".to_vec())), []);
+        assert_eq!(
+            out(TtyChunk::StdErr(b"oops\nMARK 42\nlate".to_vec())),
+            [("err", "oops\n".to_owned())]
+        );
+        assert_eq!(out(TtyChunk::StdOut(b"late".to_vec())), []);
+        assert_eq!(output.status(), Some(42));
+        assert_eq!(texts(output.finish()), []);
+    }
+
+    #[test]
+    fn test_snippet_output_unfinished() {
+        let mut output = SnippetOutput::new(b"MARK");
+        assert_eq!(texts(output.push(TtyChunk::StdOut(b"MAR".to_vec()))), []);
+        assert_eq!(
+            texts(output.push(TtyChunk::StdErr(b"MARK 0\n".to_vec()))),
+            []
+        );
+        // The interpreter died before finishing stdout
+        assert_eq!(output.status(), None);
+        assert_eq!(texts(output.finish()), [("out", "MAR".to_owned())]);
+    }
+
+    #[test]
+    fn test_run_ids() {
+        let run_ids = RunIds::default();
+        let first = run_ids.next().unwrap();
+        assert!(first.starts_with("codie-"), "{}", first);
+        assert_ne!(run_ids.next().unwrap(), first);
+        run_ids.close();
+        assert!(matches!(run_ids.next(), Err(RunError::ShuttingDown)));
+
+        assert_eq!(RunIds::new("bot_2".to_owned()).unwrap().instance(), "bot_2");
+        for bad in ["", "a-b", "a=b", "a/b"] {
+            assert!(RunIds::new(bad.to_owned()).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_error() {
+        let builds = InFlightBuilds::default();
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (fail_tx, fail_rx) = tokio::sync::oneshot::channel::<()>();
+        let first = builds.coalesce("codie/python:abc", async {
+            started_tx.send(()).unwrap();
+            fail_rx.await.unwrap();
+            Err(RunError::build("no space left on device"))
+        });
+        let second = async {
+            started_rx.await.unwrap();
+            let waiting = builds.coalesce("codie/python:abc", async {
+                panic!("started a second build");
+            });
+            fail_tx.send(()).unwrap();
+            waiting.await
+        };
+        let (first, second) = tokio::join!(first, second);
+        for result in [first, second] {
+            match result {
+                Err(err @ RunError::Build(_)) => {
+                    assert!(
+                        err.to_string().contains("no space left on device"),
+                        "{}",
+                        err
+                    )
+                }
+                result => panic!("expected a build error, got {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_termination() {
+        assert_eq!(
+            Termination::from_exit(Exit::status(0)),
+            Termination::Exited(0)
+        );
+        // Programs can exit with these themselves
+        assert_eq!(
+            Termination::from_exit(Exit::status(130)),
+            Termination::Exited(130)
+        );
+        assert_eq!(
+            Termination::from_exit(Exit::status(137)),
+            Termination::Exited(137)
+        );
+        let killed = Exit {
+            status: 137,
+            signal: Some(9),
+        };
+        assert_eq!(Termination::from_exit(killed), Termination::Signal(9));
+
+        let cases = [
+            (Termination::Exited(1), "exited with status 1"),
+            (
+                Termination::TimedOut(Duration::from_secs(10)),
+                "killed: exceeded 10s time limit",
+            ),
+            (
+                Termination::OutputOverflow(64 * 1024),
+                "killed: exceeded 64 KiB output limit",
+            ),
+            (Termination::OutOfMemory, "killed: ran out of memory"),
+            (Termination::Signal(11), "killed by SIGSEGV"),
+            (Termination::Signal(40), "killed by signal 40"),
+        ];
+        for (termination, text) in cases {
+            assert_eq!(termination.to_string(), text);
+        }
+
+        let output = Output {
+            status: 137,
+            termination: Termination::TimedOut(Duration::from_secs(10)),
+            tty: vec![],
+            usage: None,
+            files: Default::default(),
+        };
+        assert_eq!(
+            output.to_string(),
+            "**EXIT STATUS:** 137 (killed: exceeded 10s time limit)\n```\n```"
+        );
+    }
+
+    #[test]
+    fn test_render_output_mode() {
+        let report = Report {
+            compile: None,
+            run: Some(Output {
+                status: 1,
+                termination: Termination::Exited(1),
+                tty: vec![
+                    Chunk::Stdout("a\n".into()),
+                    Chunk::Stderr("b\n".into()),
+                    Chunk::Stdout("c\n".into()),
+                ],
+                usage: None,
+                files: Default::default(),
+            }),
+        };
+        assert_eq!(
+            report.render(OutputMode::Interleaved).to_string(),
+            "**EXIT STATUS:** 1\n```\na\nb\nc\n```"
+        );
+        assert_eq!(
+            report.render(OutputMode::Stdout).to_string(),
+            "**EXIT STATUS:** 1\n```\na\nc\n```"
+        );
+        assert_eq!(
+            report.render(OutputMode::Split).to_string(),
+            "**EXIT STATUS:** 1\n**STDOUT:**\n```\na\nc\n```\n**STDERR:**\n```\nb\n```"
+        );
+
+        // Empty streams are left out
+        let report = Report {
+            compile: None,
+            run: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stderr("oops\n".into())],
+                usage: None,
+                files: Default::default(),
+            }),
+        };
         assert_eq!(
-            output,
-            Output {
+            report.render(OutputMode::Split).to_string(),
+            "**STDERR:**\n```\noops\n```"
+        );
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview("short", 10), "short");
+        let text = "a".repeat(100) + &"b".repeat(100);
+        let shown = preview(&text, 100);
+        assert!(shown.chars().count() <= 100, "{}", shown);
+        assert!(shown.starts_with('a') && shown.ends_with('b'), "{}", shown);
+        assert!(shown.contains("characters omitted"), "{}", shown);
+    }
+
+    #[test]
+    fn test_share_budget() {
+        assert_eq!(share_budget(&[10, 20], 100), vec![10, 20]);
+        assert_eq!(share_budget(&[10, 200], 100), vec![10, 90]);
+        assert_eq!(share_budget(&[200, 300], 100), vec![50, 50]);
+        assert_eq!(share_budget(&[], 100), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_attachment() {
+        let short = Report {
+            compile: None,
+            run: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![Chunk::Stdout("Hello, World!\n".into())],
+                usage: None,
+                files: Default::default(),
+            }),
+        };
+        assert_eq!(short.attachment(OutputMode::Interleaved), None);
+
+        let long = "x\n".repeat(MAX_OUTPUT_CODEPOINTS);
+        let report = Report {
+            compile: None,
+            run: Some(Output {
                 status: 0,
-                tty: "x".repeat(1000).into(),
+                termination: Termination::Exited(0),
+                tty: vec![
+                    Chunk::Stdout(long.clone().into()),
+                    Chunk::Stderr("oops\n".into()),
+                ],
+                usage: None,
+                files: Default::default(),
+            }),
+        };
+        assert_eq!(
+            report.attachment(OutputMode::Interleaved),
+            Some(format!("{}oops\n", long).into_bytes())
+        );
+        assert_eq!(
+            report.attachment(OutputMode::Split),
+            Some(format!("==> STDOUT <==\n{}==> STDERR <==\noops\n", long).into_bytes())
+        );
+        let message = report.render(OutputMode::Split).to_string();
+        assert!(
+            message.chars().count() <= serenity::constants::MESSAGE_CODE_LIMIT,
+            "{}",
+            message
+        );
+        assert!(
+            message.ends_with("**STDERR:**\n```\noops\n```"),
+            "{}",
+            message
+        );
+    }
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(Vec::new());
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            archive.append_data(&mut header, path, *contents).unwrap();
+        }
+        archive.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_output_files_from_archive() {
+        let file = |path: &str, contents: &[u8]| OutputFile {
+            path: path.to_owned(),
+            contents: contents.to_vec(),
+        };
+        let mut dir = output_dir_archive();
+        // Without the end of archive marker
+        dir.truncate(512);
+        dir.extend(archive(&[
+            ("out/a.txt", b"abc"),
+            ("out/sub/b.txt", b"hello"),
+            ("out/c.txt", b"0123456789"),
+        ]));
+        assert_eq!(
+            OutputFiles::from_archive(&dir, 0, 0).files,
+            [
+                file("a.txt", b"abc"),
+                file("sub/b.txt", b"hello"),
+                file("c.txt", b"0123456789"),
+            ]
+        );
+        // Smaller files after a big one still fit
+        assert_eq!(
+            OutputFiles::from_archive(&dir, 0, 9),
+            OutputFiles {
+                files: vec![file("a.txt", b"abc"), file("sub/b.txt", b"hello")],
+                truncated: true,
             }
         );
+        assert_eq!(
+            OutputFiles::from_archive(&dir, 1, 0),
+            OutputFiles {
+                files: vec![file("a.txt", b"abc")],
+                truncated: true,
+            }
+        );
+        // Like when we stop copying partway through
+        let cut = &dir[..dir.len() - 1024 - 512];
+        assert_eq!(
+            OutputFiles::from_archive(cut, 0, 0),
+            OutputFiles {
+                files: vec![file("a.txt", b"abc"), file("sub/b.txt", b"hello")],
+                truncated: true,
+            }
+        );
+        assert_eq!(OutputFiles::from_archive(&[], 0, 0), Default::default());
+    }
+
+    async fn build_output(chunks: Vec<TtyChunk>) -> Vec<Chunk> {
+        let mut builder = OutputBuilder::new(stream::iter(chunks.into_iter().map(Ok)), 1024);
+        builder.extend().await.unwrap();
+        builder.build()
+    }
+
+    #[tokio::test]
+    async fn test_broken_logs() {
+        // Like the daemon dropping the connection partway through
+        let logs = stream::iter([
+            Ok(TtyChunk::StdOut(b"hi\n".to_vec())),
+            Err(shiplift::Error::ConnectionNotUpgraded),
+        ]);
+        let phase = Phase {
+            cmd: vec!["true"],
+            env: &[],
+            stdin: None,
+            timeout: Duration::from_secs(10),
+            compiling: false,
+        };
+        let killed = AtomicBool::new(false);
+        let result = supervise(
+            &"test",
+            logs,
+            &phase,
+            1024,
+            None,
+            || async { Ok(Exit::status(0)) },
+            || async {
+                killed.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(RunError::Daemon(_))), "{:?}", result);
+        assert!(killed.load(Ordering::SeqCst));
+
+        let logs = stream::iter([Ok(TtyChunk::StdIn(b"hi\n".to_vec()))]);
+        let mut builder = OutputBuilder::new(logs, 1024);
+        assert!(matches!(builder.extend().await, Err(RunError::Daemon(_))));
+    }
+
+    #[tokio::test]
+    async fn test_split_characters() {
+        let e_acute = "é".as_bytes();
+        let crab = "🦀".as_bytes();
+        let chunks = vec![
+            TtyChunk::StdOut(vec![b'a', e_acute[0]]),
+            TtyChunk::StdErr(crab[..3].to_vec()),
+            TtyChunk::StdOut(e_acute[1..].to_vec()),
+            TtyChunk::StdErr(crab[3..].to_vec()),
+        ];
+        let mut builder = OutputBuilder::new(stream::iter(chunks.into_iter().map(Ok)), 1024);
+        builder.extend().await.unwrap();
+        // Nothing is shown until it's whole
+        assert_eq!(
+            builder.snapshot(),
+            [Chunk::Stdout("aé".into()), Chunk::Stderr("🦀".into())]
+        );
+        let output = Output {
+            status: 0,
+            termination: Termination::Exited(0),
+            tty: builder.build(),
+            usage: None,
+            files: Default::default(),
+        };
+        assert_eq!(output.text(), "aé🦀");
+        assert_eq!(output.len(OutputMode::Interleaved), 3);
+        assert!(!output.is_binary(OutputMode::Interleaved));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        let chunks = vec![
+            TtyChunk::StdOut(vec![0xff, b'a', 0xc3]),
+            TtyChunk::StdErr(b"b".to_vec()),
+        ];
+        // Kept as they are, including a character that never got finished
+        assert_eq!(
+            build_output(chunks).await,
+            [
+                Chunk::Stdout(vec![0xff, b'a']),
+                Chunk::Stderr(b"b".to_vec()),
+                Chunk::Stdout(vec![0xc3]),
+            ]
+        );
+        assert_eq!(complete_len(&[0xff, b'a', 0xf0, 0x9f]), 2);
+        assert_eq!(complete_len(&[0xf0, 0x9f, b'a']), 3);
+    }
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(hexdump(b""), "");
+        assert_eq!(
+            hexdump(b"Hello, World!\n\x00\xffmore"),
+            "00000000  48 65 6c 6c 6f 2c 20 57  6f 72 6c 64 21 0a 00 ff  |Hello, World!...|\n\
+             00000010  6d 6f 72 65                                       |more|\n"
+        );
+    }
+
+    #[test]
+    fn test_attachment_binary() {
+        let binary = [b'a', 0xff].repeat(MAX_OUTPUT_CODEPOINTS);
+        let report = Report {
+            compile: None,
+            run: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![
+                    Chunk::Stdout(binary.clone()),
+                    Chunk::Stderr("oops\n".into()),
+                ],
+                usage: None,
+                files: Default::default(),
+            }),
+        };
+        let mut interleaved = binary.clone();
+        interleaved.extend_from_slice(b"oops\n");
+        assert_eq!(
+            report.attachment(OutputMode::Interleaved),
+            Some(hexdump(&interleaved).into_bytes())
+        );
+        // Only the section that isn't text is dumped
+        let split = format!("==> STDOUT <==\n{}==> STDERR <==\noops\n", hexdump(&binary));
+        assert_eq!(
+            report.attachment(OutputMode::Split),
+            Some(split.clone().into_bytes())
+        );
+        assert_eq!(report.attachment(OutputMode::Hex), Some(split.into_bytes()));
+        assert_eq!(
+            report.attachment(OutputMode::Stdout),
+            Some(hexdump(&binary).into_bytes())
+        );
+    }
+
+    #[test]
+    fn test_render_binary() {
+        let report = Report {
+            compile: None,
+            run: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![
+                    Chunk::Stdout(vec![b'a', 0xff]),
+                    Chunk::Stderr("oops\n".into()),
+                ],
+                usage: None,
+                files: Default::default(),
+            }),
+        };
+        assert_eq!(
+            report.to_string(),
+            format!("```\na\u{FFFD}oops\n```{}", BINARY_OUTPUT)
+        );
+        assert_eq!(
+            report.render(OutputMode::Hex).to_string(),
+            "**STDOUT:**\n```\n\
+             00000000  61 ff                                             |a.|\n```\n\
+             **STDERR:**\n```\noops\n```"
+        );
+    }
+
+    #[test]
+    fn test_render_files_truncated() {
+        let report = Report {
+            compile: None,
+            run: Some(Output {
+                status: 0,
+                termination: Termination::Exited(0),
+                tty: vec![],
+                usage: None,
+                files: OutputFiles {
+                    files: vec![],
+                    truncated: true,
+                },
+            }),
+        };
+        assert_eq!(report.to_string(), format!("```\n```{}", FILES_TRUNCATED));
+    }
+
+    #[test]
+    fn test_render_progress() {
+        let progress = Progress {
+            compiling: false,
+            tty: vec![Chunk::Stdout("a\n".into()), Chunk::Stderr("b\n".into())],
+            elapsed: Duration::from_millis(3500),
+        };
+        assert_eq!(
+            progress.render(OutputMode::Split),
+            "**STDOUT:**\n```\na\n```\n**STDERR:**\n```\nb\n```\n*running… (3s)*"
+        );
+
+        let progress = Progress {
+            compiling: true,
+            tty: vec![],
+            elapsed: Duration::from_secs(1),
+        };
+        assert_eq!(
+            progress.render(OutputMode::Split),
+            "**COMPILER OUTPUT:**\n```\n```\n*compiling… (1s)*"
+        );
+    }
+
+    #[test]
+    fn test_image_tag() {
+        let spec = |dockerfile: &str| RunSpec {
+            code_path: "run.sh",
+            compile: None,
+            run: vec![],
+            image_name: "sh".to_owned(),
+            dockerfile: dockerfile.to_owned(),
+        };
+        let image = spec("FROM alpine:3.15").image();
+        assert!(image.starts_with("codie/sh:"), "{}", image);
+        assert_eq!(image.len(), "codie/sh:".len() + 12);
+        assert_eq!(image, spec("FROM alpine:3.15").image());
+        assert_ne!(image, spec("FROM alpine:3.16").image());
     }
 }
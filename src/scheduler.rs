@@ -0,0 +1,185 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use thiserror::Error;
+use tokio::sync::Notify;
+
+#[derive(Debug, Error)]
+#[error("the queue is full")]
+pub struct QueueFull;
+
+/// Limits how many runs happen at once. Everyone else waits their turn in line
+#[derive(Debug)]
+pub struct Scheduler {
+    max_running: usize,
+    max_queued: usize,
+    state: Mutex<State>,
+    /// Notified whenever someone leaves the line or finishes running
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: usize,
+    queue: VecDeque<u64>,
+    next_id: u64,
+}
+
+impl Scheduler {
+    pub fn new(max_running: usize, max_queued: usize) -> Self {
+        Self {
+            max_running,
+            max_queued,
+            state: Mutex::new(State::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Gets in line, unless the line is already too long
+    pub fn enqueue(&self) -> Result<Ticket<'_>, QueueFull> {
+        let mut state = self.state.lock().unwrap();
+        // Whoever can start right away doesn't count against the line
+        let free = self.max_running.saturating_sub(state.running);
+        if state.queue.len() >= self.max_queued + free {
+            return Err(QueueFull);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push_back(id);
+        Ok(Ticket {
+            scheduler: self,
+            id,
+            position: None,
+            done: false,
+        })
+    }
+}
+
+/// A place in line
+#[derive(Debug)]
+pub struct Ticket<'a> {
+    scheduler: &'a Scheduler,
+    id: u64,
+    /// The last position we told the caller about
+    position: Option<usize>,
+    done: bool,
+}
+
+pub enum Turn<'a> {
+    /// Still waiting at this place in line, counting from 1
+    Queued(usize),
+    Ready(Permit<'a>),
+}
+
+impl<'a> Ticket<'a> {
+    /// Waits until it's our turn or our place in line changes
+    pub async fn advance(&mut self) -> Turn<'a> {
+        assert!(!self.done, "advanced a ticket after its turn");
+        let scheduler = self.scheduler;
+        loop {
+            // We have to listen before looking so that we don't miss a change in between
+            let changed = scheduler.changed.notified();
+            {
+                let mut state = scheduler.state.lock().unwrap();
+                let position = state.queue.iter().position(|&id| id == self.id).unwrap();
+                if position == 0 && state.running < scheduler.max_running {
+                    state.queue.pop_front();
+                    state.running += 1;
+                    self.done = true;
+                    // The next person in line moved up
+                    scheduler.changed.notify_waiters();
+                    return Turn::Ready(Permit { scheduler });
+                }
+                let position = position + 1;
+                if self.position != Some(position) {
+                    self.position = Some(position);
+                    return Turn::Queued(position);
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        // Give up our place in line if we never got our turn
+        if !self.done {
+            let mut state = self.scheduler.state.lock().unwrap();
+            state.queue.retain(|&id| id != self.id);
+            self.scheduler.changed.notify_waiters();
+        }
+    }
+}
+
+/// Permission to run. Lets the next person in line go when dropped
+#[derive(Debug)]
+pub struct Permit<'a> {
+    scheduler: &'a Scheduler,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.scheduler.state.lock().unwrap().running -= 1;
+        self.scheduler.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn ready(ticket: &mut Ticket<'_>) -> Option<usize> {
+        match ticket.advance().await {
+            Turn::Queued(position) => Some(position),
+            Turn::Ready(permit) => {
+                std::mem::forget(permit);
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_runs_immediately() {
+        let scheduler = Scheduler::new(1, 1);
+        let mut ticket = scheduler.enqueue().unwrap();
+        assert!(matches!(ticket.advance().await, Turn::Ready(_)));
+    }
+
+    #[tokio::test]
+    async fn test_positions() {
+        let scheduler = Scheduler::new(1, 2);
+        let mut first = scheduler.enqueue().unwrap();
+        let permit = match first.advance().await {
+            Turn::Ready(permit) => permit,
+            Turn::Queued(position) => panic!("queued at {}", position),
+        };
+        let mut second = scheduler.enqueue().unwrap();
+        let mut third = scheduler.enqueue().unwrap();
+        assert_eq!(ready(&mut second).await, Some(1));
+        assert_eq!(ready(&mut third).await, Some(2));
+
+        // The line is full
+        assert!(scheduler.enqueue().is_err());
+
+        // Nothing changes until the first run finishes
+        let waiting = tokio::time::timeout(Duration::from_millis(50), third.advance()).await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        assert_eq!(ready(&mut second).await, None);
+        assert_eq!(ready(&mut third).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_leave_queue() {
+        let scheduler = Scheduler::new(0, 2);
+        let mut first = scheduler.enqueue().unwrap();
+        let mut second = scheduler.enqueue().unwrap();
+        assert_eq!(ready(&mut first).await, Some(1));
+        assert_eq!(ready(&mut second).await, Some(2));
+        drop(first);
+        assert_eq!(ready(&mut second).await, Some(1));
+    }
+}
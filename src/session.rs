@@ -0,0 +1,389 @@
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, UserId};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    IVec, Tree,
+};
+
+use crate::backend::Backend;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// How long a session lasts after its last run. Zero means until it's ended
+    pub idle_secs: u64,
+    /// How many sessions can be running at once. Zero means no limit
+    pub max: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            idle_secs: 10 * 60,
+            max: 8,
+        }
+    }
+}
+
+/// Whose session it is. Each user gets one in each channel
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SessionKey {
+    pub user: UserId,
+    pub channel: ChannelId,
+}
+
+impl SessionKey {
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.user.0.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.channel.0.to_le_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// What the backend calls it
+    pub id: String,
+    /// The language it runs, as the language displays itself
+    pub lang: String,
+    pub last_used_ms: u64,
+}
+
+impl Session {
+    /// A session that was just started
+    pub fn new(id: String, lang: String) -> Self {
+        Self {
+            id,
+            lang,
+            last_used_ms: now_ms(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        serde_json::from_slice(bytes).unwrap()
+    }
+}
+
+/// Why a session can't be started
+#[derive(Debug, Eq, PartialEq)]
+pub enum CantStart {
+    /// There's already this one
+    Exists(Session),
+    /// There are `SessionConfig::max` sessions running
+    Full,
+}
+
+/// Where the number of sessions is kept, so that it can be checked and changed in the same
+/// transaction as the sessions. Session keys are 16 bytes, so it can't be mistaken for one
+const COUNT_KEY: &[u8] = b"count";
+
+/// Everyone's sessions, kept in sled. Backends lose their sessions when we restart, so these are
+/// cleared at startup
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    tree: Tree,
+    conf: SessionConfig,
+}
+
+impl Sessions {
+    pub fn new(tree: Tree, conf: SessionConfig) -> Self {
+        Self { tree, conf }
+    }
+
+    pub fn conf(&self) -> SessionConfig {
+        self.conf
+    }
+
+    pub fn get(&self, key: SessionKey) -> sled::Result<Option<Session>> {
+        Ok(self
+            .tree
+            .get(key.to_bytes())?
+            .map(|v| Session::from_bytes(&v)))
+    }
+
+    /// Whether `key` could start a session right now. `insert` checks again, since sessions can
+    /// start and end while one is being started
+    pub fn check_start(&self, key: SessionKey) -> sled::Result<Result<(), CantStart>> {
+        if let Some(session) = self.get(key)? {
+            return Ok(Err(CantStart::Exists(session)));
+        }
+        if self.conf.max > 0 && count(self.tree.get(COUNT_KEY)?) >= self.conf.max {
+            return Ok(Err(CantStart::Full));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Records `session` as `key`'s, unless `key` got another one or we filled up in the meantime
+    pub fn insert(
+        &self,
+        key: SessionKey,
+        session: &Session,
+    ) -> sled::Result<Result<(), CantStart>> {
+        let result = self.tree.transaction(|tx| {
+            if let Some(other) = tx.get(key.to_bytes())? {
+                return Err(ConflictableTransactionError::Abort(CantStart::Exists(
+                    Session::from_bytes(&other),
+                )));
+            }
+            let count = count(tx.get(COUNT_KEY)?);
+            if self.conf.max > 0 && count >= self.conf.max {
+                return Err(ConflictableTransactionError::Abort(CantStart::Full));
+            }
+            tx.insert(&key.to_bytes(), session.to_bytes())?;
+            tx.insert(COUNT_KEY, &count_bytes(count + 1))?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(Ok(())),
+            Err(TransactionError::Abort(cant)) => Ok(Err(cant)),
+            Err(TransactionError::Storage(err)) => Err(err),
+        }
+    }
+
+    /// Restarts the idle timeout of `key`'s session if it's still `id`. Returns whether it is
+    pub fn touch(&self, key: SessionKey, id: &str) -> sled::Result<bool> {
+        self.touch_at(key, id, now_ms())
+    }
+
+    fn touch_at(&self, key: SessionKey, id: &str, now: u64) -> sled::Result<bool> {
+        let new = self.tree.update_and_fetch(key.to_bytes(), |old| {
+            let mut session = Session::from_bytes(old?);
+            if session.id == id {
+                session.last_used_ms = now;
+            }
+            Some(session.to_bytes())
+        })?;
+        Ok(new.is_some_and(|new| Session::from_bytes(&new).id == id))
+    }
+
+    /// Forgets `key`'s session, returning what it was
+    pub fn remove(&self, key: SessionKey) -> sled::Result<Option<Session>> {
+        self.remove_if(&key.to_bytes(), |_| true)
+    }
+
+    /// Forgets `key`'s session if it's still `id`, like once it's found to have ended
+    pub fn forget(&self, key: SessionKey, id: &str) -> sled::Result<()> {
+        // Not matching means someone else already dealt with it
+        self.remove_if(&key.to_bytes(), |session| session.id == id)?;
+        Ok(())
+    }
+
+    /// Forgets the session at `key` if there is one and it `matches`, returning it
+    fn remove_if(
+        &self,
+        key: &[u8],
+        matches: impl Fn(&Session) -> bool,
+    ) -> sled::Result<Option<Session>> {
+        let result = self.tree.transaction(|tx| {
+            let session = match tx.get(key)? {
+                Some(value) => Session::from_bytes(&value),
+                None => return Ok(None),
+            };
+            if !matches(&session) {
+                return Ok(None);
+            }
+            tx.remove(key)?;
+            let count = count(tx.get(COUNT_KEY)?);
+            tx.insert(COUNT_KEY, &count_bytes(count.saturating_sub(1)))?;
+            Ok::<_, ConflictableTransactionError<Infallible>>(Some(session))
+        });
+        match result {
+            Ok(session) => Ok(session),
+            Err(TransactionError::Abort(never)) => match never {},
+            Err(TransactionError::Storage(err)) => Err(err),
+        }
+    }
+
+    pub fn clear(&self) -> sled::Result<()> {
+        self.tree.clear()
+    }
+
+    /// Forgets every session that's been idle for too long by `now`, returning them
+    fn take_idle(&self, now: u64) -> sled::Result<Vec<Session>> {
+        if self.conf.idle_secs == 0 {
+            return Ok(Vec::new());
+        }
+        let mut idle = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            if key == COUNT_KEY {
+                continue;
+            }
+            let session = Session::from_bytes(&value);
+            if now.saturating_sub(session.last_used_ms) < self.conf.idle_secs * 1000 {
+                continue;
+            }
+            // Unless it was used in the meantime
+            if let Some(session) = self.remove_if(&key, |current| *current == session)? {
+                idle.push(session);
+            }
+        }
+        Ok(idle)
+    }
+
+    /// Ends sessions once they've been idle for too long. Never returns
+    pub async fn expire_idle(self, backend: Arc<dyn Backend>) {
+        // How often to look for idle sessions
+        const INTERVAL: Duration = Duration::from_secs(30);
+        let mut ticks = tokio::time::interval(INTERVAL);
+        loop {
+            ticks.tick().await;
+            let idle = match self.take_idle(now_ms()) {
+                Ok(idle) => idle,
+                Err(err) => {
+                    tracing::error!("Failed to look for idle sessions: {}", err);
+                    continue;
+                }
+            };
+            for session in idle {
+                tracing::info!("Ending idle session {}", session.id);
+                if let Err(err) = backend.end_session(&session.id).await {
+                    tracing::warn!("Failed to end session {}: {}", session.id, err);
+                }
+            }
+        }
+    }
+}
+
+fn count(value: Option<IVec>) -> usize {
+    value.map_or(0, |v| {
+        u64::from_le_bytes(v.as_ref().try_into().unwrap()) as usize
+    })
+}
+
+fn count_bytes(count: usize) -> [u8; 8] {
+    (count as u64).to_le_bytes()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: SessionKey = SessionKey {
+        user: UserId(1),
+        channel: ChannelId(2),
+    };
+
+    fn sessions(conf: SessionConfig) -> Sessions {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Sessions::new(db.open_tree("sessions").unwrap(), conf)
+    }
+
+    fn session(id: &str, last_used_ms: u64) -> Session {
+        Session {
+            id: id.to_owned(),
+            lang: "Python".to_owned(),
+            last_used_ms,
+        }
+    }
+
+    #[test]
+    fn test_one_per_channel() {
+        let sessions = sessions(Default::default());
+        assert_eq!(sessions.check_start(KEY).unwrap(), Ok(()));
+        assert_eq!(sessions.insert(KEY, &session("a", 0)).unwrap(), Ok(()));
+        assert_eq!(
+            sessions.check_start(KEY).unwrap(),
+            Err(CantStart::Exists(session("a", 0)))
+        );
+        assert_eq!(
+            sessions.insert(KEY, &session("b", 0)).unwrap(),
+            Err(CantStart::Exists(session("a", 0)))
+        );
+        // The same user can have one somewhere else
+        let elsewhere = SessionKey {
+            channel: ChannelId(3),
+            ..KEY
+        };
+        assert_eq!(sessions.check_start(elsewhere).unwrap(), Ok(()));
+
+        // Only the session that ended is forgotten
+        sessions.forget(KEY, "b").unwrap();
+        assert_eq!(sessions.get(KEY).unwrap(), Some(session("a", 0)));
+        sessions.forget(KEY, "a").unwrap();
+        assert_eq!(sessions.get(KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn test_max() {
+        let sessions = sessions(SessionConfig {
+            max: 1,
+            ..Default::default()
+        });
+        sessions.insert(KEY, &session("a", 0)).unwrap().unwrap();
+        let other = SessionKey {
+            user: UserId(4),
+            ..KEY
+        };
+        assert_eq!(sessions.check_start(other).unwrap(), Err(CantStart::Full));
+        // Like when both passed `check_start` before either was inserted
+        assert_eq!(
+            sessions.insert(other, &session("b", 0)).unwrap(),
+            Err(CantStart::Full)
+        );
+
+        // Forgetting a session that's already gone doesn't make room
+        sessions.forget(KEY, "b").unwrap();
+        assert_eq!(sessions.check_start(other).unwrap(), Err(CantStart::Full));
+        assert_eq!(sessions.remove(KEY).unwrap(), Some(session("a", 0)));
+        assert_eq!(sessions.remove(KEY).unwrap(), None);
+        assert_eq!(sessions.check_start(other).unwrap(), Ok(()));
+        sessions.insert(other, &session("b", 0)).unwrap().unwrap();
+        assert_eq!(sessions.check_start(KEY).unwrap(), Err(CantStart::Full));
+    }
+
+    #[test]
+    fn test_take_idle() {
+        let sessions = sessions(SessionConfig {
+            idle_secs: 10,
+            max: 2,
+        });
+        let other = SessionKey {
+            user: UserId(4),
+            ..KEY
+        };
+        sessions.insert(KEY, &session("a", 0)).unwrap().unwrap();
+        sessions.insert(other, &session("b", 0)).unwrap().unwrap();
+        assert!(sessions.touch_at(other, "b", 5_000).unwrap());
+        // It's not a session that's still around
+        assert!(!sessions.touch_at(other, "c", 6_000).unwrap());
+        assert_eq!(sessions.take_idle(9_999).unwrap(), vec![]);
+        assert_eq!(sessions.take_idle(10_000).unwrap(), vec![session("a", 0)]);
+        assert_eq!(sessions.get(KEY).unwrap(), None);
+        assert_eq!(
+            sessions.take_idle(15_000).unwrap(),
+            vec![session("b", 5_000)]
+        );
+        assert!(!sessions.touch_at(other, "b", 16_000).unwrap());
+        // Taking them made room
+        assert_eq!(sessions.check_start(KEY).unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn test_no_idle_timeout() {
+        let sessions = sessions(SessionConfig {
+            idle_secs: 0,
+            ..Default::default()
+        });
+        sessions.insert(KEY, &session("a", 0)).unwrap().unwrap();
+        assert_eq!(sessions.take_idle(u64::MAX).unwrap(), vec![]);
+    }
+}